@@ -0,0 +1,55 @@
+use std::{
+    fs::File,
+    io::{self, prelude::*},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd}
+};
+
+use ipcd::flag::{F_GETNREAD, F_GETPIPE_SZ, F_SETPIPE_SZ};
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn nonblock(file: &File) -> io::Result<()> {
+    syscall::fcntl(file.as_raw_fd() as usize, syscall::F_SETFL, syscall::O_NONBLOCK)
+        .map(|_| ())
+        .map_err(from_syscall_error)
+}
+fn dup(file: &File, buf: &str) -> io::Result<File> {
+    let stream = syscall::dup(file.as_raw_fd() as usize, buf.as_bytes()).map_err(from_syscall_error)?;
+    Ok(unsafe { File::from_raw_fd(stream as RawFd) })
+}
+
+fn main() -> io::Result<()> {
+    let mut buf = [0; 8];
+    let server = File::create("chan:")?;
+
+    let mut client = dup(&server, "connect")?;
+    assert_eq!(syscall::fcntl(server.as_raw_fd() as usize, F_GETNREAD, 0).map_err(from_syscall_error)?, 1);
+    let mut stream = dup(&server, "listen")?;
+
+    println!("Testing capacity...");
+
+    syscall::fcntl(stream.as_raw_fd() as usize, F_SETPIPE_SZ, 4).map_err(from_syscall_error)?;
+    assert_eq!(syscall::fcntl(stream.as_raw_fd() as usize, F_GETPIPE_SZ, 0).map_err(from_syscall_error)?, 4);
+
+    nonblock(&client)?;
+
+    assert_eq!(client.write(b"abcdef")?, 4);
+    assert_eq!(syscall::fcntl(stream.as_raw_fd() as usize, F_GETNREAD, 0).map_err(from_syscall_error)?, 4);
+    println!("-> Short write");
+
+    assert_eq!(client.write(b"ef").unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    println!("-> Write would block");
+
+    assert_eq!(stream.read(&mut buf[..2])?, 2);
+    assert_eq!(&buf[..2], b"ab");
+
+    assert_eq!(client.write(b"efgh")?, 2);
+    println!("-> Write after drain");
+
+    assert_eq!(stream.read(&mut buf)?, 4);
+    assert_eq!(&buf[..4], b"cdef");
+
+    println!("Everything tested!");
+    Ok(())
+}
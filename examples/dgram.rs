@@ -0,0 +1,57 @@
+use std::{
+    fs::File,
+    io::{self, prelude::*},
+    mem,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd}
+};
+
+use ipcd::flag::{F_SETSOCKTYPE, SOCK_DGRAM};
+
+// Address in front of every datagram, see src/chan.rs
+const ADDR_LEN: usize = mem::size_of::<usize>();
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn dup(file: &File, buf: &str) -> io::Result<File> {
+    let stream = syscall::dup(file.as_raw_fd() as usize, buf.as_bytes()).map_err(from_syscall_error)?;
+    Ok(unsafe { File::from_raw_fd(stream as RawFd) })
+}
+
+fn main() -> io::Result<()> {
+    let mut buf = [0; 32];
+
+    let unbound = File::create("chan:")?;
+    syscall::fcntl(unbound.as_raw_fd() as usize, F_SETSOCKTYPE, SOCK_DGRAM).map_err(from_syscall_error)?;
+    let mut server = dup(&unbound, "hello_dgram")?;
+
+    println!("Testing multiple senders...");
+
+    let mut one = File::open("chan:hello_dgram")?;
+    let mut two = File::open("chan:hello_dgram")?;
+
+    one.write_all(b"ping")?;
+    two.write_all(b"status")?;
+
+    let len = server.read(&mut buf)?;
+    assert_eq!(&buf[ADDR_LEN..len], b"ping");
+    let mut sender_one = [0; ADDR_LEN];
+    sender_one.copy_from_slice(&buf[..ADDR_LEN]);
+
+    let len = server.read(&mut buf)?;
+    assert_eq!(&buf[ADDR_LEN..len], b"status");
+    println!("-> Received both, in order");
+
+    println!("Testing reply...");
+
+    let mut reply = sender_one.to_vec();
+    reply.extend_from_slice(b"pong");
+    server.write_all(&reply)?;
+
+    assert_eq!(one.read(&mut buf)?, 4);
+    assert_eq!(&buf[..4], b"pong");
+    println!("-> Reply went to the first sender");
+
+    println!("Everything tested!");
+    Ok(())
+}
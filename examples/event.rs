@@ -5,7 +5,7 @@ use std::{
 };
 
 fn from_syscall_error(error: syscall::Error) -> io::Error {
-    io::Error::from_raw_os_error(error.errno as i32)
+    io::Error::from_raw_os_error(error.errno)
 }
 fn nonblock(file: &File) -> io::Result<()> {
     syscall::fcntl(file.as_raw_fd() as usize, syscall::F_SETFL, syscall::O_NONBLOCK)
@@ -26,25 +26,25 @@ fn main() -> io::Result<()> {
     let mut time_file = File::open(format!("time:{}", syscall::CLOCK_MONOTONIC))?;
 
     let mut time = syscall::TimeSpec::default();
-    time_file.read(&mut time)?;
+    time_file.read_exact(&mut time)?;
     time.tv_sec += 1;
-    time_file.write(&time)?;
+    time_file.write_all(&time)?;
     time.tv_sec += 2;
-    time_file.write(&time)?;
+    time_file.write_all(&time)?;
     time.tv_sec += 2;
-    time_file.write(&time)?;
+    time_file.write_all(&time)?;
 
     const TOKEN_TIMER: usize = 0;
     const TOKEN_STREAM: usize = 1;
     const TOKEN_SERVER: usize = 2;
     const TOKEN_CLIENT: usize = 3;
 
-    event_file.write(&syscall::Event {
+    event_file.write_all(&syscall::Event {
         id: time_file.as_raw_fd() as usize,
         flags: syscall::EVENT_READ,
         data: TOKEN_TIMER
     })?;
-    event_file.write(&syscall::Event {
+    event_file.write_all(&syscall::Event {
         id: server.as_raw_fd() as usize,
         flags: syscall::EVENT_WRITE | syscall::EVENT_READ,
         data: TOKEN_SERVER
@@ -54,19 +54,19 @@ fn main() -> io::Result<()> {
 
     println!("Testing accept events...");
 
-    event_file.read(&mut event)?;
+    event_file.read_exact(&mut event)?;
     assert_eq!(event.data, TOKEN_TIMER);
     assert_eq!(event.flags, syscall::EVENT_READ);
     println!("-> Timed out");
 
     let mut client = File::open("chan:hello_world")?;
-    event_file.write(&syscall::Event {
+    event_file.write_all(&syscall::Event {
         id: client.as_raw_fd() as usize,
         flags: syscall::EVENT_WRITE | syscall::EVENT_READ,
         data: TOKEN_CLIENT
     })?;
 
-    event_file.read(&mut event)?;
+    event_file.read_exact(&mut event)?;
     assert_eq!(event.data, TOKEN_SERVER);
     assert_eq!(event.flags, syscall::EVENT_WRITE | syscall::EVENT_READ);
     println!("-> Accept event");
@@ -75,34 +75,34 @@ fn main() -> io::Result<()> {
 
     let mut stream = dup(&server, "listen")?;
 
-    event_file.read(&mut event)?;
+    event_file.read_exact(&mut event)?;
     assert_eq!(event.data, TOKEN_CLIENT);
     assert_eq!(event.flags, syscall::EVENT_WRITE);
     println!("-> Writable event");
 
-    event_file.write(&syscall::Event {
+    event_file.write_all(&syscall::Event {
         id: stream.as_raw_fd() as usize,
         flags: syscall::EVENT_READ | syscall::EVENT_WRITE,
         data: TOKEN_STREAM
     })?;
 
-    event_file.read(&mut event)?;
+    event_file.read_exact(&mut event)?;
     assert_eq!(event.data, TOKEN_STREAM);
     assert_eq!(event.flags, syscall::EVENT_WRITE);
     println!("-> Writable event");
 
-    event_file.read(&mut event)?;
+    event_file.read_exact(&mut event)?;
     assert_eq!(event.data, TOKEN_TIMER);
     assert_eq!(event.flags, syscall::EVENT_READ);
     println!("-> Timed out");
 
     println!("Testing read events...");
 
-    client.write(b"a")?;
+    client.write_all(b"a")?;
 
     let mut buf = [0; 5];
 
-    event_file.read(&mut event)?;
+    event_file.read_exact(&mut event)?;
     assert_eq!(event.data, TOKEN_STREAM);
     assert_eq!(event.flags, syscall::EVENT_READ);
     println!("-> Readable event");
@@ -110,9 +110,9 @@ fn main() -> io::Result<()> {
     assert_eq!(stream.read(&mut buf)?, 1);
     assert_eq!(buf[0], b'a');
 
-    stream.write(b"b")?;
+    stream.write_all(b"b")?;
 
-    event_file.read(&mut event)?;
+    event_file.read_exact(&mut event)?;
     assert_eq!(event.data, TOKEN_CLIENT);
     assert_eq!(event.flags, syscall::EVENT_READ);
     println!("-> Readable event");
@@ -122,13 +122,13 @@ fn main() -> io::Result<()> {
 
     drop(client);
 
-    event_file.read(&mut event)?;
+    event_file.read_exact(&mut event)?;
     assert_eq!(event.data, TOKEN_STREAM);
     println!("-> Readable event (EOF)");
 
     assert_eq!(stream.read(&mut buf)?, 0);
 
-    event_file.read(&mut event)?;
+    event_file.read_exact(&mut event)?;
     assert_eq!(event.data, TOKEN_TIMER);
     println!("-> Timed out");
 
@@ -0,0 +1,79 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, prelude::*},
+    os::unix::{fs::OpenOptionsExt, io::{AsRawFd, FromRawFd, RawFd}},
+    thread,
+    time::Duration,
+};
+
+use ipcd::flag::F_SETSEMAPHORE;
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn fcntl(file: &File, cmd: usize, arg: usize) -> io::Result<usize> {
+    syscall::fcntl(file.as_raw_fd() as usize, cmd, arg).map_err(from_syscall_error)
+}
+fn dup(file: &File, buf: &str) -> io::Result<File> {
+    let stream = syscall::dup(file.as_raw_fd() as usize, buf.as_bytes()).map_err(from_syscall_error)?;
+    Ok(unsafe { File::from_raw_fd(stream as RawFd) })
+}
+fn add(file: &mut File, value: u64) -> io::Result<()> {
+    file.write_all(&value.to_ne_bytes())?;
+    Ok(())
+}
+fn take(file: &mut File) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    file.read_exact(&mut buf)?;
+    Ok(u64::from_ne_bytes(buf))
+}
+
+fn main() -> io::Result<()> {
+    println!("Testing counting...");
+
+    let mut counter = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(syscall::O_NONBLOCK as i32)
+        .open("eventfd:")?;
+    assert_eq!(take(&mut counter).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    add(&mut counter, 2)?;
+    add(&mut counter, 3)?;
+    assert_eq!(take(&mut counter)?, 5);
+    assert_eq!(take(&mut counter).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    println!("-> Read returns and resets the count");
+
+    add(&mut counter, u64::MAX - 1)?;
+    assert_eq!(add(&mut counter, 1).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    take(&mut counter)?;
+    println!("-> Writes wait instead of overflowing");
+
+    println!("Testing semaphore mode...");
+
+    fcntl(&counter, F_SETSEMAPHORE, 1)?;
+    add(&mut counter, 2)?;
+    assert_eq!(take(&mut counter)?, 1);
+    assert_eq!(take(&mut counter)?, 1);
+    assert_eq!(take(&mut counter).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    println!("-> Read takes one at a time");
+
+    println!("Testing shared counters...");
+
+    let mut waiter = File::open("eventfd:doorbell")?;
+    let mut ringer = OpenOptions::new().write(true).open("eventfd:doorbell")?;
+    let ring = thread::spawn(move || -> io::Result<()> {
+        thread::sleep(Duration::from_millis(100));
+        add(&mut ringer, 1)
+    });
+    assert_eq!(take(&mut waiter)?, 1);
+    ring.join().unwrap()?;
+    println!("-> Woken through a named counter");
+
+    let mut copy = dup(&counter, "")?;
+    add(&mut copy, 1)?;
+    assert_eq!(take(&mut counter)?, 1);
+    println!("-> Woken through a duplicated handle");
+
+    println!("Everything tested!");
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use std::{
+    fs::File,
+    io::{self, prelude::*},
+    os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd}
+};
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn dup(file: &File, buf: &str) -> io::Result<File> {
+    let stream = syscall::dup(file.as_raw_fd() as usize, buf.as_bytes()).map_err(from_syscall_error)?;
+    Ok(unsafe { File::from_raw_fd(stream as RawFd) })
+}
+
+fn main() -> io::Result<()> {
+    let mut buf = [0; 128];
+    let server = File::create("chan:")?;
+
+    let mut client = dup(&server, "connect")?;
+    let mut stream = dup(&server, "listen")?;
+
+    println!("Testing sending a file descriptor...");
+
+    let shm = File::open("shm:fdpass")?;
+    client.write_all(b"abc")?;
+    syscall::sendfd(client.as_raw_fd() as usize, shm.into_raw_fd() as usize, 0, 0).map_err(from_syscall_error)?;
+    client.write_all(b"def")?;
+
+    assert_eq!(stream.read(&mut buf)?, 3);
+    assert_eq!(&buf[..3], b"abc");
+    println!("-> Read stops in front of the file descriptor");
+
+    let received = dup(&stream, "recvfd")?;
+    let len = syscall::fpath(received.as_raw_fd() as usize, &mut buf).map_err(from_syscall_error)?;
+    assert_eq!(&buf[..len], b"shm:fdpass");
+    println!("-> Received file descriptor");
+
+    assert_eq!(stream.read(&mut buf)?, 3);
+    assert_eq!(&buf[..3], b"def");
+
+    println!("Everything tested!");
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use std::{
+    fs::File,
+    io,
+    os::unix::io::AsRawFd,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Not exported by the syscall crate, see src/shm.rs
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn futex(file: &File, op: usize, offset: usize, value: usize, timeout: Duration) -> io::Result<usize> {
+    let mut buf = Vec::new();
+    for field in [op, offset, value, timeout.as_nanos() as usize] {
+        buf.extend_from_slice(&field.to_ne_bytes());
+    }
+    syscall::write(file.as_raw_fd() as usize, &buf).map_err(from_syscall_error)
+}
+fn map(file: &File) -> io::Result<&'static AtomicU32> {
+    let address = unsafe {
+        syscall::fmap(file.as_raw_fd() as usize, &syscall::Map {
+            offset: 0,
+            address: 0,
+            size: syscall::PAGE_SIZE,
+            flags: syscall::PROT_READ | syscall::PROT_WRITE | syscall::MAP_SHARED,
+        }).map_err(from_syscall_error)?
+    };
+    Ok(unsafe { &*(address as *const AtomicU32) })
+}
+
+fn main() -> io::Result<()> {
+    let file = File::open("shm:futex")?;
+    let word = map(&file)?;
+
+    println!("Testing value mismatch...");
+
+    word.store(1, Ordering::SeqCst);
+    assert_eq!(futex(&file, FUTEX_WAIT, 0, 0, Duration::ZERO).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    println!("-> Didn't wait");
+
+    println!("Testing timeout...");
+
+    let start = Instant::now();
+    assert_eq!(
+        futex(&file, FUTEX_WAIT, 0, 1, Duration::from_millis(100)).unwrap_err().raw_os_error(),
+        Some(syscall::ETIMEDOUT)
+    );
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    println!("-> Timed out");
+
+    println!("Testing wake...");
+
+    let waker = thread::spawn(|| -> io::Result<usize> {
+        // Another handle to the same segment, like another process would
+        // have
+        let file = File::open("shm:futex")?;
+        let word = map(&file)?;
+        thread::sleep(Duration::from_millis(100));
+        word.store(0, Ordering::SeqCst);
+        futex(&file, FUTEX_WAKE, 0, 1, Duration::ZERO)
+    });
+    futex(&file, FUTEX_WAIT, 0, 1, Duration::ZERO)?;
+    assert_eq!(waker.join().unwrap()?, 1);
+    assert_eq!(word.load(Ordering::SeqCst), 0);
+    println!("-> Woken");
+
+    assert_eq!(futex(&file, FUTEX_WAKE, 0, 1, Duration::ZERO)?, 0);
+    assert_eq!(futex(&file, FUTEX_WAIT, 2, 0, Duration::ZERO).unwrap_err().raw_os_error(), Some(syscall::EINVAL));
+    println!("-> Nothing to wake, misaligned words refused");
+
+    println!("Everything tested!");
+    Ok(())
+}
@@ -5,7 +5,7 @@ use std::{
 };
 
 fn from_syscall_error(error: syscall::Error) -> io::Error {
-    io::Error::from_raw_os_error(error.errno as i32)
+    io::Error::from_raw_os_error(error.errno)
 }
 
 fn main() -> io::Result<()> {
@@ -15,6 +15,6 @@ fn main() -> io::Result<()> {
         let stream = syscall::dup(server.as_raw_fd() as usize, b"listen").map_err(from_syscall_error)?;
         let mut stream = unsafe { File::from_raw_fd(stream as RawFd) };
 
-        stream.write(b"Hello World!\n")?;
+        stream.write_all(b"Hello World!\n")?;
     }
 }
@@ -0,0 +1,57 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+    thread,
+    time::Duration,
+};
+
+use ipcd::flag::{F_FLOCK, F_GETFLOCK, F_SETRCVTIMEO, LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN};
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn fcntl(file: &File, cmd: usize, arg: usize) -> io::Result<usize> {
+    syscall::fcntl(file.as_raw_fd() as usize, cmd, arg).map_err(from_syscall_error)
+}
+
+fn main() -> io::Result<()> {
+    println!("Testing shared locks...");
+
+    let first = OpenOptions::new().read(true).write(true).open("lock:pkg")?;
+    let second = File::open("lock:pkg")?;
+    fcntl(&first, F_FLOCK, LOCK_SH)?;
+    fcntl(&second, F_FLOCK, LOCK_SH)?;
+    assert_eq!(fcntl(&second, F_GETFLOCK, 0)?, LOCK_SH);
+    println!("-> Held by both");
+
+    println!("Testing exclusive locks...");
+
+    assert_eq!(fcntl(&second, F_FLOCK, LOCK_EX).unwrap_err().raw_os_error(), Some(syscall::EBADF));
+    println!("-> Needs a handle opened for writing");
+
+    let writer = OpenOptions::new().write(true).open("lock:pkg")?;
+    assert_eq!(fcntl(&writer, F_FLOCK, LOCK_EX | LOCK_NB).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    fcntl(&writer, F_SETRCVTIMEO, 100)?;
+    assert_eq!(fcntl(&writer, F_FLOCK, LOCK_EX).unwrap_err().raw_os_error(), Some(syscall::ETIMEDOUT));
+    println!("-> Timed out while shared");
+
+    fcntl(&first, F_FLOCK, LOCK_UN)?;
+    fcntl(&writer, F_SETRCVTIMEO, 0)?;
+    let unlocker = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        // Closing releases the lock like unlocking does
+        drop(second);
+    });
+    fcntl(&writer, F_FLOCK, LOCK_EX)?;
+    unlocker.join().unwrap();
+    assert_eq!(fcntl(&first, F_FLOCK, LOCK_SH | LOCK_NB).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    println!("-> Granted once the last shared holder closed");
+
+    drop(writer);
+    fcntl(&first, F_FLOCK, LOCK_EX | LOCK_NB)?;
+    println!("-> Released on close");
+
+    println!("Everything tested!");
+    Ok(())
+}
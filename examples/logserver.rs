@@ -7,7 +7,7 @@ use std::{
 };
 
 fn from_syscall_error(error: syscall::Error) -> io::Error {
-    io::Error::from_raw_os_error(error.errno as i32)
+    io::Error::from_raw_os_error(error.errno)
 }
 
 fn main() -> io::Result<()> {
@@ -17,7 +17,7 @@ fn main() -> io::Result<()> {
     syscall::fcntl(server.as_raw_fd() as usize, syscall::F_SETFL, syscall::O_NONBLOCK)
         .map(|_| ())
         .map_err(from_syscall_error)?;
-    event_file.write(&syscall::Event {
+    event_file.write_all(&syscall::Event {
         id: server.as_raw_fd() as usize,
         data: 0,
         flags: syscall::EVENT_READ | syscall::EVENT_WRITE,
@@ -28,7 +28,7 @@ fn main() -> io::Result<()> {
 
     'outer: loop {
         let mut event = syscall::Event::default();
-        event_file.read(&mut event)?;
+        event_file.read_exact(&mut event)?;
 
         if event.data == 0 {
             println!("Listener recevied flags: {:?}", event.flags);
@@ -40,7 +40,7 @@ fn main() -> io::Result<()> {
                     };
                     let stream = unsafe { File::from_raw_fd(stream as RawFd) };
 
-                    event_file.write(&syscall::Event {
+                    event_file.write_all(&syscall::Event {
                         id: stream.as_raw_fd() as usize,
                         data: next_id,
                         flags: syscall::EVENT_READ | syscall::EVENT_WRITE,
@@ -76,7 +76,7 @@ fn main() -> io::Result<()> {
                 const BUF: &str = "Hello from the log server\n";
                 let mut written = 0;
                 while written < BUF.len() {
-                    let len = match client.write(BUF[written..].as_bytes()) {
+                    let len = match client.write(&BUF.as_bytes()[written..]) {
                         Ok(0) => panic!("EOF should never happen here"),
                         Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
                         len => len?
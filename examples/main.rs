@@ -7,7 +7,7 @@ use std::{
 };
 
 fn from_syscall_error(error: syscall::Error) -> io::Error {
-    io::Error::from_raw_os_error(error.errno as i32)
+    io::Error::from_raw_os_error(error.errno)
 }
 fn nonblock(file: &File) -> io::Result<()> {
     syscall::fcntl(file.as_raw_fd() as usize, syscall::F_SETFL, syscall::O_NONBLOCK)
@@ -44,7 +44,7 @@ fn main() -> io::Result<()> {
 
         println!("Testing basic I/O...");
 
-        stream.write(b"abc")?;
+        stream.write_all(b"abc")?;
         stream.flush()?;
         println!("-> Wrote message");
 
@@ -58,6 +58,31 @@ fn main() -> io::Result<()> {
         assert_eq!(stream.write(b"a").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
         assert_eq!(stream.read(&mut buf)?, 0);
     }
+    println!("Testing closing a listener with pending connections...");
+    {
+        let server = File::create("chan:hello_refused")?;
+        let mut client = File::open("chan:hello_refused")?;
+        drop(server);
+
+        assert_eq!(client.write(b"a").unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(client.read(&mut buf)?, 0);
+    }
+    println!("Testing directory listing...");
+    {
+        let _nested = File::create("chan:hello_dir/nested")?;
+
+        // Other programs might have listeners of their own
+        let names = std::fs::read_dir("chan:")?
+            .map(|entry| entry.map(|entry| entry.file_name().into_string().unwrap()))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert!(names.iter().any(|name| name == "hello_dir"));
+        assert!(names.iter().any(|name| name == "hello_world"));
+
+        let names = std::fs::read_dir("chan:hello_dir")?
+            .map(|entry| entry.map(|entry| entry.file_name().into_string().unwrap()))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(names, ["nested"]);
+    }
     println!("Testing alternative connect method...");
 
     let mut client = dup(&server, "connect")?;
@@ -71,7 +96,7 @@ fn main() -> io::Result<()> {
         println!("--> Thread: Sleeping for 1 second...");
         thread::sleep(Duration::from_secs(1));
         println!("--> Thread: Writing...");
-        client_clone.write(b"def")?;
+        client_clone.write_all(b"def")?;
         client_clone.flush()?;
         Ok(())
     });
@@ -0,0 +1,84 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, prelude::*},
+    mem,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+};
+
+use ipcd::flag::{F_GETCURMSGS, F_GETMAXMSG, F_GETMSGSIZE};
+
+// Priority in front of every message, see src/mq.rs
+const PRIO_LEN: usize = mem::size_of::<usize>();
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn fcntl(file: &File, cmd: usize, arg: usize) -> io::Result<usize> {
+    syscall::fcntl(file.as_raw_fd() as usize, cmd, arg).map_err(from_syscall_error)
+}
+fn send(queue: &mut File, prio: usize, message: &[u8]) -> io::Result<()> {
+    let mut buf = prio.to_ne_bytes().to_vec();
+    buf.extend_from_slice(message);
+    queue.write_all(&buf)?;
+    Ok(())
+}
+fn receive(queue: &mut File) -> io::Result<(usize, Vec<u8>)> {
+    let mut buf = [0; PRIO_LEN + 16];
+    let len = queue.read(&mut buf)?;
+    let mut prio = [0; PRIO_LEN];
+    prio.copy_from_slice(&buf[..PRIO_LEN]);
+    Ok((usize::from_ne_bytes(prio), buf[PRIO_LEN..len].to_vec()))
+}
+
+fn main() -> io::Result<()> {
+    println!("Testing creation...");
+
+    let mut sender = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(syscall::O_NONBLOCK as i32)
+        .mode(0o600)
+        .open("mq:jobs?2,16")?;
+    assert_eq!(
+        OpenOptions::new().write(true).create_new(true).open("mq:jobs").unwrap_err().kind(),
+        io::ErrorKind::AlreadyExists
+    );
+    let mut receiver = OpenOptions::new()
+        .read(true)
+        .custom_flags(syscall::O_NONBLOCK as i32)
+        .open("mq:jobs")?;
+    assert_eq!(fcntl(&receiver, F_GETMAXMSG, 0)?, 2);
+    assert_eq!(fcntl(&receiver, F_GETMSGSIZE, 0)?, 16);
+    assert_eq!(
+        OpenOptions::new().write(true).create_new(true).open("mq:bad?0,16").unwrap_err().raw_os_error(),
+        Some(syscall::EINVAL)
+    );
+    println!("-> Created with attributes");
+
+    println!("Testing priorities...");
+
+    send(&mut sender, 1, b"low")?;
+    send(&mut sender, 5, b"high")?;
+    assert_eq!(fcntl(&sender, F_GETCURMSGS, 0)?, 2);
+    assert_eq!(send(&mut sender, 3, b"full").unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    assert_eq!(receive(&mut receiver)?, (5, b"high".to_vec()));
+    assert_eq!(receive(&mut receiver)?, (1, b"low".to_vec()));
+    assert_eq!(receive(&mut receiver).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    println!("-> Highest priority first");
+
+    assert_eq!(send(&mut sender, 0, &[0; 17]).unwrap_err().raw_os_error(), Some(syscall::EMSGSIZE));
+    let mut small = [0; PRIO_LEN + 15];
+    assert_eq!(receiver.read(&mut small).unwrap_err().raw_os_error(), Some(syscall::EMSGSIZE));
+    println!("-> Message size enforced");
+
+    println!("Testing unlink...");
+
+    send(&mut sender, 0, b"still here")?;
+    fs::remove_file("mq:jobs")?;
+    assert_eq!(File::open("mq:jobs").unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert_eq!(receive(&mut receiver)?, (0, b"still here".to_vec()));
+    println!("-> Open handles keep the queue");
+
+    println!("Everything tested!");
+    Ok(())
+}
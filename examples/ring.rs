@@ -0,0 +1,46 @@
+use std::{
+    io::{self, prelude::*},
+    thread,
+};
+
+use ipcd::ringbuf::{Consumer, Producer};
+
+const TOTAL: usize = 1024 * 1024;
+
+fn main() -> io::Result<()> {
+    println!("Testing streaming...");
+
+    let mut consumer = Consumer::open("frames", 4096)?;
+    let producer = thread::spawn(|| -> io::Result<()> {
+        let mut producer = Producer::open("frames", 4096)?;
+        let data: Vec<u8> = (0..TOTAL).map(|i| i as u8).collect();
+        producer.write_all(&data)
+    });
+
+    let mut received = 0;
+    loop {
+        let data = consumer.peek()?;
+        if data.is_empty() {
+            break;
+        }
+        for (i, &byte) in data.iter().enumerate() {
+            assert_eq!(byte, (received + i) as u8);
+        }
+        let len = data.len();
+        received += len;
+        consumer.consume(len)?;
+    }
+    producer.join().unwrap()?;
+    assert_eq!(received, TOTAL);
+    println!("-> Received everything, then EOF");
+
+    println!("Testing a closed consumer...");
+
+    let mut producer = Producer::open("frames", 4096)?;
+    drop(consumer);
+    assert_eq!(producer.write_all(&[0; 8192]).unwrap_err().raw_os_error(), Some(syscall::EPIPE));
+    println!("-> EPIPE once full");
+
+    println!("Everything tested!");
+    Ok(())
+}
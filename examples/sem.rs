@@ -0,0 +1,83 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, prelude::*},
+    mem,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    thread,
+    time::Duration,
+};
+
+use ipcd::flag::{F_GETVALUE, F_SETRCVTIMEO};
+
+// Count read and written, see src/sem.rs
+const COUNT_LEN: usize = mem::size_of::<usize>();
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn fcntl(file: &File, cmd: usize, arg: usize) -> io::Result<usize> {
+    syscall::fcntl(file.as_raw_fd() as usize, cmd, arg).map_err(from_syscall_error)
+}
+fn post(sem: &mut File) -> io::Result<()> {
+    sem.write_all(&1usize.to_ne_bytes())?;
+    Ok(())
+}
+fn wait(sem: &mut File) -> io::Result<()> {
+    let mut buf = [0; COUNT_LEN];
+    sem.read_exact(&mut buf)?;
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    println!("Testing creation...");
+
+    let mut sem = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open("sem:counter?1")?;
+    assert_eq!(fcntl(&sem, F_GETVALUE, 0)?, 1);
+    let mut other = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags((syscall::O_NONBLOCK | syscall::O_CREAT) as i32)
+        .open("sem:counter?5")?;
+    assert_eq!(fcntl(&other, F_GETVALUE, 0)?, 1);
+    println!("-> Created with initial value, which doesn't apply to existing ones");
+
+    println!("Testing try-wait...");
+
+    wait(&mut other)?;
+    assert_eq!(wait(&mut other).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    println!("-> Taken, then EAGAIN");
+
+    println!("Testing timed wait...");
+
+    fcntl(&sem, F_SETRCVTIMEO, 100)?;
+    assert_eq!(wait(&mut sem).unwrap_err().raw_os_error(), Some(syscall::ETIMEDOUT));
+    println!("-> Timed out");
+
+    println!("Testing blocking wait...");
+
+    fcntl(&sem, F_SETRCVTIMEO, 0)?;
+    let poster = thread::spawn(move || -> io::Result<()> {
+        thread::sleep(Duration::from_millis(100));
+        post(&mut other)
+    });
+    wait(&mut sem)?;
+    poster.join().unwrap()?;
+    assert_eq!(fcntl(&sem, F_GETVALUE, 0)?, 0);
+    println!("-> Woken by post");
+
+    println!("Testing unlink...");
+
+    fs::remove_file("sem:counter")?;
+    assert_eq!(File::open("sem:counter").unwrap_err().kind(), io::ErrorKind::NotFound);
+    post(&mut sem)?;
+    wait(&mut sem)?;
+    println!("-> Open handles keep the semaphore");
+
+    println!("Everything tested!");
+    Ok(())
+}
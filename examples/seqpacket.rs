@@ -0,0 +1,58 @@
+use std::{
+    fs::File,
+    io::{self, prelude::*},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd}
+};
+
+use ipcd::flag::{F_GETSOCKTYPE, F_GETTRUNC, F_SETSOCKTYPE, SOCK_SEQPACKET};
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn dup(file: &File, buf: &str) -> io::Result<File> {
+    let stream = syscall::dup(file.as_raw_fd() as usize, buf.as_bytes()).map_err(from_syscall_error)?;
+    Ok(unsafe { File::from_raw_fd(stream as RawFd) })
+}
+
+fn main() -> io::Result<()> {
+    let mut buf = [0; 8];
+
+    println!("Testing binding a packet listener...");
+
+    let unbound = File::create("chan:")?;
+    syscall::fcntl(unbound.as_raw_fd() as usize, F_SETSOCKTYPE, SOCK_SEQPACKET).map_err(from_syscall_error)?;
+    let server = dup(&unbound, "hello_packets")?;
+
+    let mut client = File::open("chan:hello_packets")?;
+    let mut stream = dup(&server, "listen")?;
+    assert_eq!(syscall::fcntl(client.as_raw_fd() as usize, F_GETSOCKTYPE, 0).map_err(from_syscall_error)?, SOCK_SEQPACKET);
+    assert_eq!(syscall::fcntl(stream.as_raw_fd() as usize, F_GETSOCKTYPE, 0).map_err(from_syscall_error)?, SOCK_SEQPACKET);
+
+    println!("Testing picking the type at open...");
+
+    let typed = File::create("chan:typed_packets?seqpacket")?;
+    let typed_client = File::open("chan:typed_packets")?;
+    assert_eq!(syscall::fcntl(typed.as_raw_fd() as usize, F_GETSOCKTYPE, 0).map_err(from_syscall_error)?, SOCK_SEQPACKET);
+    assert_eq!(syscall::fcntl(typed_client.as_raw_fd() as usize, F_GETSOCKTYPE, 0).map_err(from_syscall_error)?, SOCK_SEQPACKET);
+    println!("-> Created as a packet listener");
+
+    println!("Testing message boundaries...");
+
+    client.write_all(b"abc")?;
+    client.write_all(b"de")?;
+    client.write_all(b"fghijklmnop")?;
+
+    assert_eq!(stream.read(&mut buf)?, 3);
+    assert_eq!(&buf[..3], b"abc");
+    assert_eq!(stream.read(&mut buf)?, 2);
+    assert_eq!(&buf[..2], b"de");
+    println!("-> Read one message at a time");
+
+    assert_eq!(stream.read(&mut buf)?, 8);
+    assert_eq!(&buf, b"fghijklm");
+    assert_eq!(syscall::fcntl(stream.as_raw_fd() as usize, F_GETTRUNC, 0).map_err(from_syscall_error)?, 3);
+    println!("-> Truncated long message");
+
+    println!("Everything tested!");
+    Ok(())
+}
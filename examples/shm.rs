@@ -6,7 +6,7 @@ use std::{
 };
 
 fn from_syscall_error(error: syscall::Error) -> io::Error {
-    io::Error::from_raw_os_error(error.errno as i32)
+    io::Error::from_raw_os_error(error.errno)
 }
 fn main() -> Result<(), io::Error> {
     let file1 = File::open("shm:example")?;
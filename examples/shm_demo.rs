@@ -8,7 +8,7 @@ use std::{
 };
 
 fn from_syscall_error(error: syscall::Error) -> io::Error {
-    io::Error::from_raw_os_error(error.errno as i32)
+    io::Error::from_raw_os_error(error.errno)
 }
 fn main() -> Result<(), io::Error> {
     let file = File::open("shm:counter")?;
@@ -0,0 +1,55 @@
+use std::{
+    fs::File,
+    io::{self, prelude::*},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd}
+};
+
+use ipcd::flag::{F_ABORT, F_SHUTDOWN, SHUT_WR};
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn dup(file: &File, buf: &str) -> io::Result<File> {
+    let stream = syscall::dup(file.as_raw_fd() as usize, buf.as_bytes()).map_err(from_syscall_error)?;
+    Ok(unsafe { File::from_raw_fd(stream as RawFd) })
+}
+
+fn main() -> io::Result<()> {
+    let mut buf = [0; 8];
+
+    println!("Testing half-close...");
+
+    let mut client = File::create("chan:")?;
+    let mut stream = dup(&client, "pair")?;
+
+    client.write_all(b"request")?;
+    syscall::fcntl(client.as_raw_fd() as usize, F_SHUTDOWN, SHUT_WR).map_err(from_syscall_error)?;
+    assert_eq!(client.write(b"a").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    println!("-> Write after shutdown fails");
+
+    let mut request = Vec::new();
+    stream.read_to_end(&mut request)?;
+    assert_eq!(request, b"request");
+    println!("-> Read until EOF");
+
+    stream.write_all(b"reply")?;
+    assert_eq!(client.read(&mut buf)?, 5);
+    assert_eq!(&buf[..5], b"reply");
+    println!("-> Other direction still open");
+
+    println!("Testing abortive close...");
+
+    let mut client = File::create("chan:")?;
+    let mut stream = dup(&client, "pair")?;
+
+    stream.write_all(b"lost")?;
+    syscall::fcntl(stream.as_raw_fd() as usize, F_ABORT, 0).map_err(from_syscall_error)?;
+    drop(stream);
+
+    assert_eq!(client.read(&mut buf).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    assert_eq!(client.read(&mut buf)?, 0);
+    println!("-> Reset reported once, then EOF");
+
+    println!("Everything tested!");
+    Ok(())
+}
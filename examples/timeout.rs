@@ -0,0 +1,57 @@
+use std::{
+    fs::File,
+    io::{self, prelude::*},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    time::{Duration, Instant}
+};
+
+use ipcd::flag::{F_GETRCVTIMEO, F_SETPIPE_SZ, F_SETRCVTIMEO, F_SETSNDTIMEO};
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn fcntl(file: &File, cmd: usize, arg: usize) -> io::Result<usize> {
+    syscall::fcntl(file.as_raw_fd() as usize, cmd, arg).map_err(from_syscall_error)
+}
+fn dup(file: &File, buf: &str) -> io::Result<File> {
+    let stream = syscall::dup(file.as_raw_fd() as usize, buf.as_bytes()).map_err(from_syscall_error)?;
+    Ok(unsafe { File::from_raw_fd(stream as RawFd) })
+}
+
+fn main() -> io::Result<()> {
+    let mut buf = [0; 4];
+
+    println!("Testing accept timeout...");
+
+    let server = File::create("chan:")?;
+    fcntl(&server, F_SETRCVTIMEO, 100)?;
+    assert_eq!(fcntl(&server, F_GETRCVTIMEO, 0)?, 100);
+
+    let start = Instant::now();
+    assert_eq!(dup(&server, "listen").unwrap_err().raw_os_error(), Some(syscall::ETIMEDOUT));
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    println!("-> Timed out");
+
+    println!("Testing read timeout...");
+
+    let mut client = dup(&server, "connect")?;
+    let mut stream = dup(&server, "listen")?;
+    assert_eq!(fcntl(&stream, F_GETRCVTIMEO, 0)?, 100);
+    println!("-> Inherited from listener");
+
+    assert_eq!(stream.read(&mut buf).unwrap_err().raw_os_error(), Some(syscall::ETIMEDOUT));
+    client.write_all(b"abc")?;
+    assert_eq!(stream.read(&mut buf)?, 3);
+    println!("-> Timed out, then read");
+
+    println!("Testing write timeout...");
+
+    fcntl(&stream, F_SETPIPE_SZ, 4)?;
+    fcntl(&client, F_SETSNDTIMEO, 100)?;
+    assert_eq!(client.write(b"abcd")?, 4);
+    assert_eq!(client.write(b"e").unwrap_err().raw_os_error(), Some(syscall::ETIMEDOUT));
+    println!("-> Timed out");
+
+    println!("Everything tested!");
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, prelude::*},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+};
+
+use ipcd::flag::{F_GETDROPPED, F_SETPIPE_SZ, F_SETPOLICY, POLICY_DISCONNECT, POLICY_DROP_OLDEST};
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+fn fcntl(file: &File, cmd: usize, arg: usize) -> io::Result<usize> {
+    syscall::fcntl(file.as_raw_fd() as usize, cmd, arg).map_err(from_syscall_error)
+}
+fn subscribe(name: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(syscall::O_NONBLOCK as i32)
+        .open(format!("topic:{}", name))
+}
+
+fn main() -> io::Result<()> {
+    let mut buf = [0; 16];
+
+    println!("Testing fan-out...");
+
+    let mut first = subscribe("news")?;
+    let mut second = subscribe("news")?;
+    let mut publisher = OpenOptions::new().write(true).open("topic:news")?;
+
+    publisher.write_all(b"hello")?;
+    publisher.write_all(b"world")?;
+    for subscriber in [&mut first, &mut second] {
+        assert_eq!(subscriber.read(&mut buf)?, 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(subscriber.read(&mut buf)?, 5);
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(subscriber.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+    println!("-> Every subscriber got every message");
+
+    println!("Testing slow subscribers...");
+
+    let mut dropping = subscribe("news")?;
+    fcntl(&dropping, F_SETPIPE_SZ, 8)?;
+    fcntl(&dropping, F_SETPOLICY, POLICY_DROP_OLDEST)?;
+    let mut cut = subscribe("news")?;
+    fcntl(&cut, F_SETPIPE_SZ, 8)?;
+    fcntl(&cut, F_SETPOLICY, POLICY_DISCONNECT)?;
+
+    publisher.write_all(b"abcd")?;
+    publisher.write_all(b"efgh")?;
+    publisher.write_all(b"ijkl")?;
+
+    assert_eq!(fcntl(&dropping, F_GETDROPPED, 0)?, 1);
+    assert_eq!(dropping.read(&mut buf)?, 4);
+    assert_eq!(&buf[..4], b"efgh");
+    println!("-> Oldest message dropped");
+
+    assert_eq!(cut.read(&mut buf).unwrap_err().raw_os_error(), Some(syscall::ECONNRESET));
+    assert_eq!(cut.read(&mut buf)?, 0);
+    println!("-> Disconnected");
+
+    println!("Testing blocking subscribers...");
+
+    drop((dropping, cut));
+    for _ in 0..3 {
+        assert_eq!(first.read(&mut buf)?, 4);
+    }
+    fcntl(&first, F_SETPIPE_SZ, 8)?;
+    let mut nonblocking = OpenOptions::new()
+        .write(true)
+        .custom_flags(syscall::O_NONBLOCK as i32)
+        .open("topic:news")?;
+    assert_eq!(nonblocking.write(b"123456789").unwrap_err().raw_os_error(), Some(syscall::EMSGSIZE));
+    nonblocking.write_all(b"1234")?;
+    nonblocking.write_all(b"5678")?;
+    assert_eq!(nonblocking.write(b"9").unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    assert_eq!(first.read(&mut buf)?, 4);
+    nonblocking.write_all(b"9")?;
+    println!("-> Publisher held back until there was space");
+
+    println!("Everything tested!");
+    Ok(())
+}
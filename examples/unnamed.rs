@@ -5,7 +5,7 @@ use std::{
 };
 
 fn from_syscall_error(error: syscall::Error) -> io::Error {
-    io::Error::from_raw_os_error(error.errno as i32)
+    io::Error::from_raw_os_error(error.errno)
 }
 fn dup(file: &File, buf: &str) -> io::Result<File> {
     let stream = syscall::dup(file.as_raw_fd() as usize, buf.as_bytes()).map_err(from_syscall_error)?;
@@ -21,7 +21,7 @@ fn main() -> io::Result<()> {
 
     println!("Testing basic I/O...");
 
-    stream.write(b"abc")?;
+    stream.write_all(b"abc")?;
     stream.flush()?;
     println!("-> Wrote message");
 
@@ -32,6 +32,34 @@ fn main() -> io::Result<()> {
     println!("Testing connecting to unnamed socket by name (makes no sense)...");
     assert_eq!(File::open("chan:").unwrap_err().kind(), io::ErrorKind::NotFound);
 
+    println!("Testing socket pairs...");
+
+    let mut one = File::create("chan:")?;
+    let mut two = dup(&one, "pair")?;
+
+    one.write_all(b"abc")?;
+    assert_eq!(two.read(&mut buf)?, 3);
+    assert_eq!(&buf[..3], b"abc");
+
+    two.write_all(b"de")?;
+    assert_eq!(one.read(&mut buf)?, 2);
+    assert_eq!(&buf[..2], b"de");
+    println!("-> Both ends connected");
+
+    assert!(dup(&one, "pair").is_err());
+    println!("-> Can't pair a connected handle");
+
+    println!("Testing peeking...");
+
+    let mut peek = dup(&two, "peek")?;
+    one.write_all(b"hello")?;
+    assert_eq!(peek.read(&mut buf[..2])?, 2);
+    assert_eq!(&buf[..2], b"he");
+    assert_eq!(peek.read(&mut buf)?, 5);
+    assert_eq!(two.read(&mut buf)?, 5);
+    assert_eq!(&buf, b"hello");
+    println!("-> Peeking doesn't consume");
+
     println!("Everything tested!");
     Ok(())
 }
@@ -0,0 +1,165 @@
+use std::{
+    cmp,
+    collections::VecDeque,
+};
+
+/// Size of the chunks small writes are collected in
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// How much of a receive buffer's capacity a message of `len` bytes takes
+/// up. Empty messages count as one byte, so they can't pile up without
+/// bound
+pub fn message_cost(len: usize) -> usize {
+    cmp::max(len, 1)
+}
+
+/// A byte queue made of separately allocated chunks, so taking data out
+/// of the front never moves what's left, and memory is given back as soon
+/// as a chunk is read.
+#[derive(Debug, Default)]
+pub struct Buffer {
+    chunks: VecDeque<Vec<u8>>,
+    /// How much of the first chunk was already taken out
+    head: usize,
+    len: usize
+}
+impl Buffer {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append data to the end. It goes into the space left in the last
+    /// chunk, so that chunk is never reallocated, and whatever doesn't fit
+    /// gets a new chunk.
+    pub fn push(&mut self, mut data: &[u8]) {
+        self.len += data.len();
+
+        if let Some(last) = self.chunks.back_mut() {
+            let len = cmp::min(data.len(), last.capacity() - last.len());
+            last.extend_from_slice(&data[..len]);
+            data = &data[len..];
+        }
+        if !data.is_empty() {
+            let mut chunk = Vec::with_capacity(cmp::max(data.len(), CHUNK_SIZE));
+            chunk.extend_from_slice(data);
+            self.chunks.push_back(chunk);
+        }
+    }
+
+    /// Copy data from the front into `buf` without taking it out, and
+    /// return how many bytes that was
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+        let mut skip = self.head;
+        for chunk in &self.chunks {
+            if copied == buf.len() {
+                break;
+            }
+            let chunk = &chunk[skip..];
+            skip = 0;
+            let len = cmp::min(chunk.len(), buf.len() - copied);
+            buf[copied..][..len].copy_from_slice(&chunk[..len]);
+            copied += len;
+        }
+        copied
+    }
+
+    /// Take `len` bytes out of the front, dropping every chunk that was
+    /// read completely
+    pub fn consume(&mut self, len: usize) {
+        assert!(len <= self.len, "consuming more than is buffered");
+        self.len -= len;
+        self.head += len;
+
+        while let Some(first) = self.chunks.front() {
+            if self.head < first.len() {
+                break;
+            }
+            self.head -= first.len();
+            let mut first = self.chunks.pop_front().unwrap();
+            if self.chunks.is_empty() && first.capacity() <= CHUNK_SIZE {
+                // Keep a single regular chunk around, so a connection
+                // that's read as fast as it's written doesn't allocate
+                // on every write
+                first.clear();
+                self.chunks.push_back(first);
+                break;
+            }
+        }
+    }
+
+    /// Throw away everything and give the memory back
+    pub fn clear(&mut self) {
+        self.chunks = VecDeque::new();
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consume_across_chunks() {
+        let mut buffer = Buffer::default();
+        buffer.push(&[1; CHUNK_SIZE - 2]);
+        buffer.push(&[2, 3, 4, 5]);
+        assert_eq!(buffer.chunks.len(), 2);
+
+        // One byte is left in the first chunk
+        buffer.consume(CHUNK_SIZE - 1);
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.chunks.len(), 2);
+        let mut buf = [0; 3];
+        assert_eq!(buffer.peek(&mut buf), 3);
+        assert_eq!(buf, [3, 4, 5]);
+
+        // Which goes away once it's read
+        buffer.consume(2);
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.chunks.len(), 1);
+        assert_eq!(buffer.peek(&mut buf), 1);
+        assert_eq!(buf[0], 5);
+    }
+
+    #[test]
+    fn peek_past_end() {
+        let mut buffer = Buffer::default();
+        buffer.push(b"abc");
+        buffer.push(b"de");
+
+        let mut buf = [0; 8];
+        assert_eq!(buffer.peek(&mut buf), 5);
+        assert_eq!(&buf[..5], b"abcde");
+        assert_eq!(buffer.len(), 5);
+
+        buffer.consume(5);
+        assert_eq!(buffer.peek(&mut buf), 0);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn keep_one_chunk() {
+        let mut buffer = Buffer::default();
+        buffer.push(b"abc");
+        buffer.consume(3);
+        assert_eq!(buffer.chunks.len(), 1);
+        assert_eq!(buffer.head, 0);
+
+        // Written into the chunk that was kept
+        buffer.push(b"de");
+        assert_eq!(buffer.chunks.len(), 1);
+        assert_eq!(buffer.chunks[0].capacity(), CHUNK_SIZE);
+        let mut buf = [0; 2];
+        assert_eq!(buffer.peek(&mut buf), 2);
+        assert_eq!(&buf, b"de");
+
+        buffer.clear();
+        assert!(buffer.chunks.is_empty());
+        assert!(buffer.is_empty());
+    }
+}
@@ -1,48 +1,287 @@
 use std::{
     cmp,
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
+    mem,
+    time::Duration,
 };
-use syscall::{flag::*, error::*, Error};
-use redox_scheme::{SchemeBlockMut, V2};
+use syscall::{flag::*, error::*, schemev2::NewFdFlags, Error, Stat};
+use syscall::dirent::{DirEntry, DirentBuf, DirentKind};
+use redox_scheme::{CallerCtx, OpenResult, SchemeBlockMut, V2};
+use ipcd::flag::*;
+
+use crate::buffer::{message_cost, Buffer};
+use crate::path;
+use crate::perm::{Perm, WRITE};
+use crate::wait::{BlockScheme, Blocked, SendFdRequest, Timeouts};
+
+/// Permissions of handles that aren't created with a mode, like the ones
+/// connecting to a listener, so whatever they talk to can answer
+pub const DEFAULT_PERM: u16 = 0o777;
+
+/// File type reported by fstat, missing from the syscall crate
+pub const MODE_SOCK: u16 = 0xC000;
+
+/// Number of connections that may wait to be accepted, unless changed
+/// with `F_SETBACKLOG`
+pub const DEFAULT_BACKLOG: usize = 128;
+
+/// Number of bytes a connection may buffer before writers are held back,
+/// unless changed with `F_SETPIPE_SZ`
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+/// Size of the address in front of every datagram read from, or written
+/// to, an unconnected datagram handle. The address is the native-endian
+/// handle number of the sender or the destination.
+pub const ADDR_LEN: usize = mem::size_of::<usize>();
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Mode {
+    /// Everything written ends up in one continuous byte stream
+    #[default]
+    Stream,
+    /// Every write is kept as one message, and every read returns (at
+    /// most) one message
+    Packet,
+    /// Like packet mode, but without connections. Messages are sent to
+    /// a bound name or to the handle that sent something earlier
+    Datagram
+}
+impl Mode {
+    pub fn from_sock_type(ty: usize) -> Result<Self> {
+        match ty {
+            SOCK_STREAM => Ok(Mode::Stream),
+            SOCK_DGRAM => Ok(Mode::Datagram),
+            SOCK_SEQPACKET => Ok(Mode::Packet),
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+    /// Parse the socket type a listener can be created with, as in
+    /// `chan:<name>?seqpacket`
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "stream" => Ok(Mode::Stream),
+            "dgram" => Ok(Mode::Datagram),
+            "seqpacket" => Ok(Mode::Packet),
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+    pub fn sock_type(self) -> usize {
+        match self {
+            Mode::Stream => SOCK_STREAM,
+            Mode::Datagram => SOCK_DGRAM,
+            Mode::Packet => SOCK_SEQPACKET
+        }
+    }
+}
+
+/// Who opened a handle
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cred {
+    pub pid: usize,
+    pub uid: u32,
+    pub gid: u32
+}
+impl From<&CallerCtx> for Cred {
+    fn from(ctx: &CallerCtx) -> Self {
+        Self {
+            pid: ctx.pid,
+            uid: ctx.uid,
+            gid: ctx.gid
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct Client {
-    buffer: Vec<u8>,
+    buffer: Buffer,
+    /// Lengths of the messages in `buffer`, only used in packet mode
+    packets: VecDeque<usize>,
+    /// How much of the capacity `buffer` takes up. In packet mode, every
+    /// message counts as at least one byte
+    queued: usize,
+    /// Total number of bytes ever put into and taken out of `buffer`,
+    /// used to line file descriptors up with the data
+    pushed: usize,
+    popped: usize,
+    /// File descriptors owned by ipcd waiting to be received, along with
+    /// the value of `pushed` when they were sent
+    fds: VecDeque<(usize, usize)>,
+    /// The other side couldn't write because this was full, and is told
+    /// once reading makes room
+    held_back: bool,
+    /// Credentials of the other side, known once connected
+    peer_cred: Option<Cred>,
+    /// Nothing more will arrive, reads return EOF once `buffer` is empty
+    shutdown_read: bool,
+    /// Nothing more may be sent, writes return EPIPE
+    shutdown_write: bool,
+    /// The listener this was accepted from, or is waiting to be accepted
+    /// by
+    listener: Option<usize>,
     remote: Connection
 }
-#[derive(Debug, Default)]
+impl Client {
+    /// Return how many bytes the next read of at most `max` bytes returns,
+    /// and how many it takes out of `buffer`
+    pub fn next_read(&self, mode: Mode, max: usize) -> (usize, usize) {
+        match mode {
+            Mode::Stream => {
+                let mut len = cmp::min(max, self.buffer.len());
+                // Stop in front of a file descriptor, so it's only
+                // received after the data sent before it
+                if let Some(&(pos, _)) = self.fds.iter().find(|&&(pos, _)| pos > self.popped) {
+                    len = cmp::min(len, pos - self.popped);
+                }
+                (len, len)
+            },
+            Mode::Packet | Mode::Datagram => {
+                // Whatever doesn't fit is thrown away
+                let packet_len = self.packets.front().copied().unwrap_or(0);
+                (cmp::min(max, packet_len), packet_len)
+            }
+        }
+    }
+
+    /// Return true if reading returns EOF once `buffer` is empty
+    pub fn eof(&self) -> bool {
+        self.shutdown_read || self.remote == Connection::Closed || self.remote == Connection::Refused
+    }
+
+    /// Throw away all data that hasn't been read yet. File descriptors are
+    /// kept, but can now be received
+    pub fn discard(&mut self) {
+        self.buffer.clear();
+        self.packets.clear();
+        self.queued = 0;
+        self.popped = self.pushed;
+    }
+
+    /// Return true if the next file descriptor can be received, which is
+    /// once all data sent before it was read
+    pub fn fd_ready(&self) -> bool {
+        self.fds.front().is_some_and(|&(pos, _)| pos <= self.popped)
+    }
+}
+#[derive(Debug)]
 pub struct Listener {
     path: Option<String>,
-    awaiting: VecDeque<usize>
+    awaiting: VecDeque<usize>,
+    /// How many connections may be in `awaiting` before connecting is
+    /// refused or blocks
+    backlog: usize,
+    /// How many accepted connections may be open at once, 0 for no limit
+    max_accepted: usize,
+    /// How many accepted connections are open
+    accepted: usize
+}
+impl Listener {
+    /// Return true if no more connections may be accepted right now
+    pub fn limited(&self) -> bool {
+        self.max_accepted != 0 && self.accepted >= self.max_accepted
+    }
+}
+impl Default for Listener {
+    fn default() -> Self {
+        Self {
+            path: None,
+            awaiting: VecDeque::new(),
+            backlog: DEFAULT_BACKLOG,
+            max_accepted: 0,
+            accepted: 0
+        }
+    }
+}
+#[derive(Debug, Default)]
+pub struct Datagram {
+    /// Bound name, if any
+    path: Option<String>,
+    /// Where messages go if the writer doesn't say. Set when opening a
+    /// bound name
+    peer: Option<usize>,
+    /// Received messages along with the handle that sent them
+    queue: VecDeque<(usize, Vec<u8>)>,
+    /// How much of the capacity the messages in `queue` take up, every
+    /// message counting as at least one byte
+    queued: usize,
+    /// Handles that couldn't send because `queue` was full
+    writers: Vec<usize>
+}
+#[derive(Debug, Default)]
+pub struct Directory {
+    /// Names directly inside this directory, taken when it was opened
+    entries: Vec<(String, DirentKind)>
 }
 #[derive(Debug)]
 pub enum Extra {
     Client(Client),
-    Listener(Listener)
+    Listener(Listener),
+    Datagram(Datagram),
+    Directory(Directory),
+    /// Opened with O_STAT, only good for looking at the listener with
+    /// this id
+    Stat(usize),
+    /// Reads return what the handle with this id would read, without
+    /// taking it out
+    Peek(usize)
 }
 impl Default for Extra {
     fn default() -> Self {
         Extra::Client(Client::default())
     }
 }
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub enum Connection {
+    #[default]
     Waiting,
     Open(usize),
-    Closed
-}
-impl Default for Connection {
-    fn default() -> Self {
-        Connection::Waiting
-    }
+    Closed,
+    /// The other side aborted the connection, reported as ECONNRESET
+    Reset,
+    /// The listener went away before accepting, reported as
+    /// ECONNREFUSED
+    Refused
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Handle {
     flags: usize,
+    /// Maximum size of the receive buffer. On a listener, this is what
+    /// accepted streams start out with.
+    capacity: usize,
+    /// On a listener, this is what new connections will use
+    mode: Mode,
+    /// How many bytes of the last message read did not fit
+    truncated: usize,
+    /// How long reads and accepting, or writes and connecting, may block
+    /// before failing with ETIMEDOUT. Accepted streams take these from
+    /// the listener
+    timeouts: Timeouts,
+    /// Who created this handle. Accepted streams take this from the
+    /// listener
+    cred: Cred,
+    /// Owner and permissions, checked when connecting to a listener
+    perm: Perm,
     extra: Extra,
     path: Option<String>,
 }
+impl Default for Handle {
+    fn default() -> Self {
+        Self {
+            flags: 0,
+            capacity: DEFAULT_CAPACITY,
+            mode: Mode::default(),
+            truncated: 0,
+            timeouts: Timeouts::default(),
+            cred: Cred::default(),
+            perm: Perm {
+                mode: DEFAULT_PERM,
+                ..Perm::default()
+            },
+            extra: Extra::default(),
+            path: None,
+        }
+    }
+}
 impl Handle {
     /// Duplicate this listener handle into one that is linked to the
     /// specified remote.
@@ -50,6 +289,9 @@ impl Handle {
     pub fn accept(&self, remote: usize) -> Self {
         Self {
             flags: self.flags,
+            capacity: self.capacity,
+            timeouts: self.timeouts,
+            cred: self.cred,
             extra: Extra::Client(Client {
                 remote: Connection::Open(remote),
                 ..Client::default()
@@ -86,12 +328,86 @@ impl Handle {
             _ => Err(Error::new(EBADF))
         }
     }
+
+    /// Error if this is not a datagram handle
+    pub fn require_datagram(&mut self) -> Result<&mut Datagram> {
+        match self.extra {
+            Extra::Datagram(ref mut datagram) => Ok(datagram),
+            _ => Err(Error::new(EBADF))
+        }
+    }
+
+    /// Change the mode of this handle. Only listeners without pending
+    /// connections can be changed, and switching to datagram mode turns
+    /// them into a datagram handle.
+    /// Errors if this is not a listener
+    pub fn set_mode(&mut self, mode: Mode) -> Result<()> {
+        match self.extra {
+            Extra::Listener(ref mut listener) if listener.awaiting.is_empty() => {
+                if mode == Mode::Datagram {
+                    self.extra = Extra::Datagram(Datagram {
+                        path: listener.path.take(),
+                        ..Datagram::default()
+                    });
+                }
+                self.mode = mode;
+                Ok(())
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    /// Return true if the user may connect to (or send to) this handle,
+    /// which needs write permission
+    pub fn can_connect(&self, uid: u32, gid: u32) -> bool {
+        self.perm.allows(uid, gid, WRITE)
+    }
+
+    /// Return how many more bytes fit in the receive buffer.
+    /// Listeners have no buffer and always return 0
+    pub fn space(&self) -> usize {
+        match self.extra {
+            Extra::Client(ref client) => self.capacity.saturating_sub(client.queued),
+            Extra::Datagram(ref datagram) => self.capacity.saturating_sub(datagram.queued),
+            Extra::Listener(_) | Extra::Directory(_) | Extra::Stat(_) | Extra::Peek(_) => 0
+        }
+    }
+
+    /// Return true if there is something to read. In packet mode, this
+    /// includes empty messages.
+    /// Listeners never have anything to read
+    pub fn has_data(&self) -> bool {
+        match self.extra {
+            Extra::Client(ref client) => match self.mode {
+                Mode::Stream => !client.buffer.is_empty(),
+                Mode::Packet | Mode::Datagram => !client.packets.is_empty()
+            },
+            Extra::Datagram(ref datagram) => !datagram.queue.is_empty(),
+            Extra::Listener(_) | Extra::Directory(_) | Extra::Stat(_) | Extra::Peek(_) => false
+        }
+    }
+
+    /// Return how many bytes can be read without blocking, like FIONREAD.
+    /// Message based handles only count the next message, and listeners
+    /// count the connections waiting to be accepted
+    pub fn nread(&self) -> usize {
+        match self.extra {
+            Extra::Client(ref client) => match self.mode {
+                Mode::Stream => client.buffer.len(),
+                Mode::Packet | Mode::Datagram => client.packets.front().copied().unwrap_or(0)
+            },
+            Extra::Listener(ref listener) => listener.awaiting.len(),
+            Extra::Datagram(ref datagram) => datagram.queue.front().map_or(0, |(_, payload)| payload.len()),
+            Extra::Directory(_) | Extra::Stat(_) | Extra::Peek(_) => 0
+        }
+    }
 }
 
 pub struct ChanScheme {
     handles: HashMap<usize, Handle>,
     listeners: HashMap<String, usize>,
     next_id: usize,
+    blocked: Blocked,
     pub socket: redox_scheme::Socket,
 }
 impl ChanScheme {
@@ -100,9 +416,367 @@ impl ChanScheme {
             handles: HashMap::new(),
             listeners: HashMap::new(),
             next_id: 0,
+            blocked: Blocked::default(),
             socket: redox_scheme::Socket::<V2>::nonblock("chan")?,
         })
     }
+
+    /// Post an event for a handle and wake up requests blocked on it
+    fn notify(&mut self, id: usize, events: EventFlags) -> Result<()> {
+        self.socket.post_fevent(id, events.bits())?;
+        self.wake(id, events);
+        Ok(())
+    }
+
+    /// Wake up requests blocked on a handle, without posting an event
+    fn wake(&mut self, id: usize, events: EventFlags) {
+        self.blocked.wake(id, events);
+    }
+
+    /// Leave the current request blocked until the handle gets an event
+    /// matching `events`, or until the handle's timeout for that runs out
+    fn block<T>(&mut self, id: usize, events: EventFlags) -> Result<Option<T>> {
+        let timeout = self.handles.get(&id).and_then(|handle| handle.timeouts.get(events));
+        self.block_for(id, events, timeout)
+    }
+
+    /// Like `block`, but with a timeout not taken from the handle waited on
+    fn block_for<T>(&mut self, id: usize, events: EventFlags, timeout: Option<Duration>) -> Result<Option<T>> {
+        self.blocked.block(id, events, timeout)
+    }
+
+    /// Block the current request, or fail with EAGAIN in non-blocking mode
+    fn wait<T>(&mut self, id: usize, events: EventFlags, flags: usize) -> Result<Option<T>> {
+        if flags & O_NONBLOCK == O_NONBLOCK {
+            Err(Error::new(EAGAIN))
+        } else {
+            self.block(id, events)
+        }
+    }
+
+    fn queued(&self, id: usize, events: EventFlags) -> bool {
+        self.blocked.queued(id, events)
+    }
+
+    /// Queue a message on the datagram handle it is addressed to
+    fn send_datagram(&mut self, id: usize, buf: &[u8], flags: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let cred = handle.cred;
+        let datagram = handle.require_datagram()?;
+
+        let (remote_id, payload) = match datagram.peer {
+            Some(peer) => (peer, buf),
+            None => {
+                if buf.len() < ADDR_LEN {
+                    return Err(Error::new(EDESTADDRREQ));
+                }
+                let mut addr = [0; ADDR_LEN];
+                addr.copy_from_slice(&buf[..ADDR_LEN]);
+                (usize::from_ne_bytes(addr), &buf[ADDR_LEN..])
+            }
+        };
+
+        let remote = self.handles.get_mut(&remote_id).ok_or(Error::new(ECONNREFUSED))?;
+        if !remote.can_connect(cred.uid, cred.gid) {
+            return Err(Error::new(EACCES));
+        }
+        if message_cost(payload.len()) > remote.capacity {
+            return Err(Error::new(EMSGSIZE));
+        }
+        let full = message_cost(payload.len()) > remote.space();
+        let datagram = remote.require_datagram().map_err(|_| Error::new(ECONNREFUSED))?;
+
+        if full {
+            // Remember the writer so it can be told when there is space
+            if !datagram.writers.contains(&id) {
+                datagram.writers.push(id);
+            }
+            return if (flags as usize) & O_NONBLOCK == O_NONBLOCK {
+                Err(Error::new(EAGAIN))
+            } else {
+                self.block(id, EVENT_WRITE)
+            };
+        }
+
+        datagram.queue.push_back((id, payload.to_vec()));
+        datagram.queued += message_cost(payload.len());
+        self.notify(remote_id, EVENT_READ)?;
+
+        Ok(Some(buf.len()))
+    }
+
+    /// Take the next message off a datagram handle. Unconnected handles
+    /// get the sender's address in front of it
+    fn recv_datagram(&mut self, id: usize, buf: &mut [u8], flags: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let datagram = handle.require_datagram()?;
+
+        let header = if datagram.peer.is_none() { ADDR_LEN } else { 0 };
+        if buf.len() < header {
+            return Err(Error::new(EINVAL));
+        }
+
+        if let Some((sender, payload)) = datagram.queue.pop_front() {
+            datagram.queued -= message_cost(payload.len());
+
+            buf[..header].copy_from_slice(&sender.to_ne_bytes()[..header]);
+            let len = cmp::min(payload.len(), buf.len() - header);
+            buf[header..][..len].copy_from_slice(&payload[..len]);
+
+            let writers = mem::take(&mut datagram.writers);
+            handle.truncated = payload.len() - len;
+            for writer in writers {
+                self.notify(writer, EVENT_WRITE)?;
+            }
+            Ok(Some(header + len))
+        } else if (flags as usize) & O_NONBLOCK == O_NONBLOCK {
+            Err(Error::new(EAGAIN))
+        } else {
+            self.block(id, EVENT_READ)
+        }
+    }
+
+    /// Stop data from flowing in one or both directions of a stream
+    fn shutdown(&mut self, id: usize, how: usize) -> Result<()> {
+        let (read, write) = match how {
+            SHUT_RD => (true, false),
+            SHUT_WR => (false, true),
+            SHUT_RDWR => (true, true),
+            _ => return Err(Error::new(EINVAL))
+        };
+
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let client = handle.require_client()?;
+        let remote_id = match client.remote {
+            Connection::Open(remote_id) => remote_id,
+            Connection::Waiting => return Err(Error::new(ENOTCONN)),
+            Connection::Closed | Connection::Reset | Connection::Refused => return Ok(())
+        };
+        client.shutdown_read |= read;
+        client.shutdown_write |= write;
+
+        let remote = self.handles.get_mut(&remote_id).unwrap();
+        match remote.extra {
+            Extra::Client(ref mut client) => {
+                client.shutdown_read |= write;
+                client.shutdown_write |= read;
+            },
+            _ => panic!("somehow, a client was connected to a listener directly")
+        }
+
+        // Let the other side find out about EOF or EPIPE
+        let mut events = EventFlags::empty();
+        if write {
+            events |= EVENT_READ;
+        }
+        if read {
+            events |= EVENT_WRITE;
+        }
+        self.notify(remote_id, events)?;
+        if read {
+            self.notify(id, EVENT_READ)?;
+        }
+        if write {
+            // Writes blocked on this handle fail now
+            self.wake(id, EVENT_WRITE);
+        }
+        Ok(())
+    }
+
+    /// Drop the connection of a stream right away, along with all data
+    /// not read yet. The other side gets ECONNRESET instead of EOF
+    fn abort(&mut self, id: usize) -> Result<()> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let client = handle.require_client()?;
+        let remote = mem::replace(&mut client.remote, Connection::Closed);
+        client.discard();
+
+        match remote {
+            Connection::Open(remote_id) => {
+                let remote = self.handles.get_mut(&remote_id).unwrap();
+                match remote.extra {
+                    Extra::Client(ref mut client) => {
+                        client.remote = Connection::Reset;
+                        client.discard();
+                    },
+                    _ => panic!("somehow, a client was connected to a listener directly")
+                }
+                self.notify(remote_id, EVENT_READ | EVENT_WRITE)?;
+            },
+            Connection::Waiting => if let Some(listener_id) = client.listener.take()
+                && let Some(listener) = self.handles.get_mut(&listener_id)
+                && let Extra::Listener(ref mut listener) = listener.extra {
+                // Don't let accepting bring it back
+                listener.awaiting.retain(|&client_id| client_id != id);
+                // There's room in the backlog again
+                self.wake(listener_id, EVENT_WRITE);
+            },
+            Connection::Closed | Connection::Reset | Connection::Refused => ()
+        }
+        // Whatever was blocked on this handle fails now
+        self.wake(id, EVENT_READ | EVENT_WRITE);
+        Ok(())
+    }
+
+    /// List the names directly inside a directory. Slashes in names
+    /// create directories. Returns None if the directory doesn't exist
+    fn list(&self, path: &str) -> Option<Vec<(String, DirentKind)>> {
+        let path = path.trim_end_matches('/');
+        let mut entries = BTreeMap::new();
+
+        for name in self.listeners.keys() {
+            let rest = if path.is_empty() {
+                name.as_str()
+            } else {
+                match name.strip_prefix(path).and_then(|rest| rest.strip_prefix('/')) {
+                    Some(rest) => rest,
+                    None => continue
+                }
+            };
+            let (entry, kind) = match rest.find('/') {
+                Some(i) => (&rest[..i], DirentKind::Directory),
+                None => (rest, DirentKind::Socket)
+            };
+            entries.entry(String::from(entry)).or_insert(kind);
+        }
+
+        if entries.is_empty() && !path.is_empty() {
+            return None;
+        }
+        Some(entries.into_iter().collect())
+    }
+
+    /// Return true if the listener can't take any more pending
+    /// connections. Clients that went away before being accepted don't
+    /// count
+    fn backlog_full(&self, listener_id: usize) -> bool {
+        match self.handles.get(&listener_id).map(|handle| &handle.extra) {
+            Some(Extra::Listener(listener)) => {
+                let pending = listener.awaiting.iter().filter(|id| self.handles.contains_key(id)).count();
+                pending >= listener.backlog
+            },
+            _ => false
+        }
+    }
+
+    /// Return the handle whose owner and permissions are changed through
+    /// this one, which is the listener itself for handles opened with
+    /// O_STAT, along with who's asking
+    fn owned_handle(&mut self, id: usize) -> Result<(Cred, &mut Handle)> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let cred = handle.cred;
+        let id = match handle.extra {
+            Extra::Stat(listener_id) => listener_id,
+            _ => id
+        };
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(ENOENT))?;
+        Ok((cred, handle))
+    }
+
+    /// Copy out what the next read of a stream or datagram handle would
+    /// return, but leave it there
+    fn peek(&mut self, id: usize, buf: &mut [u8], flags: u32, timeout: Option<Duration>) -> Result<Option<usize>> {
+        // Nothing more to see once the handle is closed
+        let handle = match self.handles.get(&id) {
+            Some(handle) => handle,
+            None => return Ok(Some(0))
+        };
+
+        match handle.extra {
+            Extra::Client(ref client) if handle.has_data() => {
+                let (len, _) = client.next_read(handle.mode, buf.len());
+                Ok(Some(client.buffer.peek(&mut buf[..len])))
+            },
+            Extra::Client(ref client) if client.remote == Connection::Reset => Err(Error::new(ECONNRESET)),
+            Extra::Client(ref client) if client.eof() => Ok(Some(0)),
+            Extra::Datagram(ref datagram) if !datagram.queue.is_empty() => {
+                let (sender, ref payload) = datagram.queue[0];
+                let header = if datagram.peer.is_none() { ADDR_LEN } else { 0 };
+                if buf.len() < header {
+                    return Err(Error::new(EINVAL));
+                }
+
+                buf[..header].copy_from_slice(&sender.to_ne_bytes()[..header]);
+                let len = cmp::min(payload.len(), buf.len() - header);
+                buf[header..][..len].copy_from_slice(&payload[..len]);
+                Ok(Some(header + len))
+            },
+            Extra::Client(_) | Extra::Datagram(_) => if (flags as usize) & O_NONBLOCK == O_NONBLOCK {
+                Err(Error::new(EAGAIN))
+            } else {
+                self.block_for(id, EVENT_READ, timeout)
+            },
+            _ => Err(Error::new(EBADF))
+        }
+    }
+
+    /// Record the caller as the creator of a new handle
+    fn set_cred(&mut self, id: Option<usize>, ctx: &CallerCtx) {
+        if let Some(handle) = id.and_then(|id| self.handles.get_mut(&id)) {
+            handle.cred = Cred::from(ctx);
+        }
+    }
+
+    /// Hand the next file descriptor sent by the other side over to the
+    /// caller
+    fn recv_fd(&mut self, id: usize) -> Result<Option<usize>> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let flags = handle.flags;
+        let timeout = handle.timeouts.read;
+        let client = handle.require_client()?;
+
+        if client.fd_ready() {
+            let (_, fd) = client.fds.pop_front().unwrap();
+            Ok(Some(fd))
+        } else if client.remote == Connection::Closed || client.remote == Connection::Reset || client.remote == Connection::Refused {
+            Err(Error::new(EPIPE))
+        } else if flags & O_NONBLOCK == O_NONBLOCK {
+            Err(Error::new(EAGAIN))
+        } else {
+            // Not counted as a reader, reads have to get the data in front
+            // of the file descriptor out of the way first
+            self.blocked.block_aside(id, EVENT_READ, timeout)
+        }
+    }
+}
+
+impl BlockScheme for ChanScheme {
+    fn socket(&self) -> &redox_scheme::Socket {
+        &self.socket
+    }
+    fn blocked(&mut self) -> &mut Blocked {
+        &mut self.blocked
+    }
+    /// Take ownership of a file descriptor sent over a stream and queue it
+    /// for the other side
+    fn on_sendfd(&mut self, request: &SendFdRequest) -> Result<usize> {
+        let handle = self.handles.get_mut(&request.id()).ok_or(Error::new(EBADF))?;
+        let client = handle.require_client()?;
+
+        if client.shutdown_write {
+            return Err(Error::new(EPIPE));
+        }
+        let remote_id = match client.remote {
+            Connection::Open(remote_id) => remote_id,
+            Connection::Closed => return Err(Error::new(EPIPE)),
+            Connection::Reset => return Err(Error::new(ECONNRESET)),
+            Connection::Refused => return Err(Error::new(ECONNREFUSED)),
+            Connection::Waiting => return Err(Error::new(ENOTCONN))
+        };
+
+        let fd = request.obtain_fd(&self.socket)?;
+
+        let remote = self.handles.get_mut(&remote_id).unwrap();
+        match remote.extra {
+            Extra::Client(ref mut client) => {
+                client.fds.push_back((client.pushed, fd));
+            },
+            _ => panic!("somehow, a client was connected to a listener directly")
+        }
+        self.notify(remote_id, EVENT_READ)?;
+
+        Ok(0)
+    }
 }
 
 impl SchemeBlockMut for ChanScheme {
@@ -112,20 +786,49 @@ impl SchemeBlockMut for ChanScheme {
     // | |_| |  __/| |___| |\  |
     //  \___/|_|   |_____|_| \_|
 
-    fn open(&mut self, path: &str, flags: usize, _uid: u32, _gid: u32) -> Result<Option<usize>> {
+    fn xopen(&mut self, path: &str, flags: usize, ctx: &CallerCtx) -> Result<Option<OpenResult>> {
+        let new_id = self.open(path, flags, ctx.uid, ctx.gid)?;
+        self.set_cred(new_id, ctx);
+        Ok(new_id.map(|number| OpenResult::ThisScheme { number, flags: NewFdFlags::empty() }))
+    }
+    fn open(&mut self, path: &str, flags: usize, uid: u32, gid: u32) -> Result<Option<usize>> {
         let new_id = self.next_id;
-        let mut new = Handle::default();
-        new.flags = flags;
+        let mut new = Handle {
+            flags,
+            ..Handle::default()
+        };
 
         let create = flags & O_CREAT == O_CREAT;
 
-        if create && !self.listeners.contains_key(path) {
+        // Listeners can get their socket type right away, so nothing can
+        // connect before it's set
+        let (path, mode) = match path.rsplit_once('?') {
+            Some((path, mode)) if create => (path, Some(Mode::from_name(mode)?)),
+            _ => (path, None)
+        };
+
+        if !create && flags & (O_DIRECTORY | O_STAT) != 0 && let Some(entries) = self.list(path) {
+            new.extra = Extra::Directory(Directory { entries });
+            new.path = Some(String::from(path));
+        } else if !create && flags & O_DIRECTORY == O_DIRECTORY {
+            return Err(Error::new(if self.listeners.contains_key(path) { ENOTDIR } else { ENOENT }));
+        } else if !create && flags & O_STAT == O_STAT {
+            // Don't connect to something that's only being looked at
+            let listener_id = *self.listeners.get(path).ok_or(Error::new(ENOENT))?;
+            new.extra = Extra::Stat(listener_id);
+            new.path = Some(String::from(path));
+        } else if create && !self.listeners.contains_key(path) {
+            new.perm = Perm::new(uid, gid, flags);
+
             let mut listener = Listener::default();
             if !path.is_empty() {
                 self.listeners.insert(String::from(path), new_id);
                 listener.path = Some(String::from(path));
             }
             new.extra = Extra::Listener(listener);
+            if let Some(mode) = mode {
+                new.set_mode(mode)?;
+            }
         } else if create && flags & O_EXCL == O_EXCL {
             return Err(Error::new(EEXIST));
         } else {
@@ -133,12 +836,43 @@ impl SchemeBlockMut for ChanScheme {
             // and O_EXCL isn't set
             let listener_id = *self.listeners.get(path).ok_or(Error::new(ENOENT))?;
             let listener = self.handles.get_mut(&listener_id).expect("orphan listener left over");
-            listener.connect(new_id)?;
+            if !listener.can_connect(uid, gid) {
+                return Err(Error::new(EACCES));
+            }
+            if mode.is_some_and(|mode| mode != listener.mode) {
+                return Err(Error::new(EPROTOTYPE));
+            }
+            new.mode = listener.mode;
+
+            if listener.mode == Mode::Datagram {
+                // Nothing to accept, messages go straight to the bound
+                // handle
+                new.extra = Extra::Datagram(Datagram {
+                    peer: Some(listener_id),
+                    ..Datagram::default()
+                });
+                new.path = Some(String::from(path));
+            } else {
+                if self.backlog_full(listener_id) || self.queued(listener_id, EVENT_WRITE) {
+                    return if flags & O_NONBLOCK == O_NONBLOCK {
+                        Err(Error::new(ECONNREFUSED))
+                    } else {
+                        // Nothing could have set a timeout yet
+                        self.block_for(listener_id, EVENT_WRITE, None)
+                    };
+                }
+                let listener = self.handles.get_mut(&listener_id).unwrap();
+                listener.connect(new_id)?;
+                new.extra = Extra::Client(Client {
+                    listener: Some(listener_id),
+                    ..Client::default()
+                });
 
-            // smoltcp sends writeable whenever a listener gets a
-            // client, we'll do the same too (but also readable, why
-            // not)
-            self.socket.post_fevent(listener_id, (EVENT_READ | EVENT_WRITE).bits())?;
+                // smoltcp sends writeable whenever a listener gets a
+                // client, we'll do the same too (but also readable, why
+                // not)
+                self.notify(listener_id, EVENT_READ | EVENT_WRITE)?;
+            }
         }
 
         self.handles.insert(new_id, new);
@@ -148,12 +882,17 @@ impl SchemeBlockMut for ChanScheme {
     fn dup(&mut self, id: usize, buf: &[u8]) -> Result<Option<usize>> {
         match buf {
             b"listen" => {
+                let flags = self.handles.get(&id).ok_or(Error::new(EBADF))?.flags;
+                if self.queued(id, EVENT_READ) {
+                    return self.wait(id, EVENT_READ, flags);
+                }
                 loop {
                     let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
                     let listener = handle.require_listener()?;
                     let listener_path = listener.path.clone();
+                    let limited = listener.limited();
 
-                    break if let Some(remote_id) = listener.awaiting.pop_front() {
+                    break if !limited && let Some(remote_id) = listener.awaiting.pop_front() {
                         let new_id = self.next_id;
                         let mut new = handle.accept(remote_id);
 
@@ -167,10 +906,26 @@ impl SchemeBlockMut for ChanScheme {
                         match remote.extra {
                             Extra::Client(ref mut client) => {
                                 client.remote = Connection::Open(new_id);
+                                client.peer_cred = Some(new.cred);
+                                // Only the accepted side counts against
+                                // the listener from here on
+                                client.listener = None;
                             },
-                            Extra::Listener(_) => panic!("newly created handle can't possibly be a listener")
+                            _ => panic!("newly created handle can't possibly be a listener")
+                        }
+                        new.mode = remote.mode;
+                        if let Extra::Client(ref mut client) = new.extra {
+                            client.peer_cred = Some(remote.cred);
+                            client.listener = Some(id);
+                        }
+                        self.notify(remote_id, EVENT_WRITE)?;
+
+                        if let Some(handle) = self.handles.get_mut(&id)
+                            && let Extra::Listener(ref mut listener) = handle.extra {
+                            listener.accepted += 1;
                         }
-                        self.socket.post_fevent(remote_id, EVENT_WRITE.bits())?;
+                        // There's room in the backlog again
+                        self.wake(id, EVENT_WRITE);
 
                         new.path = listener_path;
 
@@ -180,22 +935,99 @@ impl SchemeBlockMut for ChanScheme {
                     } else if handle.flags & O_NONBLOCK == O_NONBLOCK {
                         Err(Error::new(EAGAIN))
                     } else {
-                        Ok(None)
+                        self.block(id, EVENT_READ)
                     };
                 }
             },
             b"connect" => {
                 let new_id = self.next_id;
-                let new = Handle::default();
+                let mut new = Handle::default();
 
                 let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
                 handle.require_listener()?;
+                let flags = handle.flags;
+                if self.backlog_full(id) || self.queued(id, EVENT_WRITE) {
+                    return if flags & O_NONBLOCK == O_NONBLOCK {
+                        Err(Error::new(ECONNREFUSED))
+                    } else {
+                        self.block(id, EVENT_WRITE)
+                    };
+                }
+
+                let handle = self.handles.get_mut(&id).unwrap();
                 handle.connect(new_id)?;
+                new.mode = handle.mode;
+                new.extra = Extra::Client(Client {
+                    listener: Some(id),
+                    ..Client::default()
+                });
 
                 // smoltcp sends writeable whenever a listener gets a
                 // client, we'll do the same too (but also readable,
                 // why not)
-                self.socket.post_fevent(id, (EVENT_READ | EVENT_WRITE).bits())?;
+                self.notify(id, EVENT_READ | EVENT_WRITE)?;
+
+                self.handles.insert(new_id, new);
+                self.next_id += 1;
+                Ok(Some(new_id))
+            },
+            b"peek" => {
+                let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+                match handle.extra {
+                    Extra::Client(_) | Extra::Datagram(_) => (),
+                    _ => return Err(Error::new(EBADF))
+                }
+                let new = Handle {
+                    flags: handle.flags,
+                    timeouts: handle.timeouts,
+                    extra: Extra::Peek(id),
+                    path: handle.path.clone(),
+                    ..Handle::default()
+                };
+
+                let new_id = self.next_id;
+                self.handles.insert(new_id, new);
+                self.next_id += 1;
+                Ok(Some(new_id))
+            },
+            b"pair" => {
+                // Like socketpair, turn an unnamed and unused handle into
+                // one end of a connection and return the other end
+                let new_id = self.next_id;
+
+                let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+                let mut new = Handle {
+                    flags: handle.flags,
+                    capacity: handle.capacity,
+                    mode: handle.mode,
+                    timeouts: handle.timeouts,
+                    cred: handle.cred,
+                    ..Handle::default()
+                };
+
+                match handle.extra {
+                    Extra::Listener(ref listener) if listener.path.is_none() && listener.awaiting.is_empty() => {
+                        handle.extra = Extra::Client(Client {
+                            remote: Connection::Open(new_id),
+                            peer_cred: Some(new.cred),
+                            ..Client::default()
+                        });
+                        new.extra = Extra::Client(Client {
+                            remote: Connection::Open(id),
+                            peer_cred: Some(new.cred),
+                            ..Client::default()
+                        });
+                    },
+                    Extra::Datagram(ref mut datagram) if datagram.path.is_none() && datagram.peer.is_none() => {
+                        datagram.peer = Some(new_id);
+                        new.extra = Extra::Datagram(Datagram {
+                            peer: Some(id),
+                            ..Datagram::default()
+                        });
+                    },
+                    _ => return Err(Error::new(EINVAL))
+                }
+                self.notify(id, EVENT_WRITE)?;
 
                 self.handles.insert(new_id, new);
                 self.next_id += 1;
@@ -217,7 +1049,50 @@ impl SchemeBlockMut for ChanScheme {
                 }
 
                 let flags = handle.flags;
-                return self.open(path, flags, 0, 0);
+                let capacity = handle.capacity;
+                let timeouts = handle.timeouts;
+                let mode = handle.mode;
+                let perm = handle.perm;
+                let limits = match handle.extra {
+                    Extra::Listener(ref listener) => Some((listener.backlog, listener.max_accepted)),
+                    _ => None
+                };
+                let cred = handle.cred;
+                let new_id = self.open(path, flags, cred.uid, cred.gid)?;
+
+                // A new listener keeps the settings of the handle it was
+                // created from, so they can be picked before it's
+                // reachable
+                if let Some(new_id) = new_id
+                    && let Some(new) = self.handles.get_mut(&new_id)
+                    && let Extra::Listener(_) = new.extra {
+                    new.capacity = capacity;
+                    new.timeouts = timeouts;
+                    new.perm = perm;
+                    if let Some((backlog, max_accepted)) = limits {
+                        let listener = new.require_listener()?;
+                        listener.backlog = backlog;
+                        listener.max_accepted = max_accepted;
+                    }
+                    // A type given with the name was already set by open
+                    if !path.contains('?') {
+                        new.set_mode(mode)?;
+                    }
+                }
+                Ok(new_id)
+            }
+        }
+    }
+
+    fn xdup(&mut self, id: usize, buf: &[u8], ctx: &CallerCtx) -> Result<Option<OpenResult>> {
+        match buf {
+            b"recvfd" => Ok(self.recv_fd(id)?.map(|fd| OpenResult::OtherScheme { fd })),
+            // Accepted streams belong to whoever created the listener
+            b"listen" => Ok(self.dup(id, buf)?.map(|number| OpenResult::ThisScheme { number, flags: NewFdFlags::empty() })),
+            _ => {
+                let new_id = self.dup(id, buf)?;
+                self.set_cred(new_id, ctx);
+                Ok(new_id.map(|number| OpenResult::ThisScheme { number, flags: NewFdFlags::empty() }))
             }
         }
     }
@@ -229,47 +1104,135 @@ impl SchemeBlockMut for ChanScheme {
     // |___\___/   \___/\/  \____|_____\___/|____/|_____|
 
     fn write(&mut self, id: usize, buf: &[u8], _offset: u64, flags: u32) -> Result<Option<usize>> {
+        if self.queued(id, EVENT_WRITE) {
+            return self.wait(id, EVENT_WRITE, flags as usize);
+        }
         let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        if handle.mode == Mode::Datagram {
+            return self.send_datagram(id, buf, flags);
+        }
+        let mode = handle.mode;
         let client = handle.require_client()?;
 
-        if let Connection::Open(remote_id) = client.remote {
+        if client.shutdown_write {
+            Err(Error::new(EPIPE))
+        } else if let Connection::Open(remote_id) = client.remote {
             let remote = self.handles.get_mut(&remote_id).unwrap();
+            let (len, full) = match mode {
+                Mode::Stream => {
+                    let len = cmp::min(buf.len(), remote.space());
+                    (len, len == 0 && !buf.is_empty())
+                },
+                Mode::Packet | Mode::Datagram => {
+                    // Messages are never split up
+                    if message_cost(buf.len()) > remote.capacity {
+                        return Err(Error::new(EMSGSIZE));
+                    }
+                    (buf.len(), message_cost(buf.len()) > remote.space())
+                }
+            };
+            if full {
+                // Buffer is full, wait for the reader to drain it
+                if let Extra::Client(ref mut client) = remote.extra {
+                    client.held_back = true;
+                }
+                return if (flags as usize) & O_NONBLOCK == O_NONBLOCK {
+                    Err(Error::new(EAGAIN))
+                } else {
+                    self.block(id, EVENT_WRITE)
+                };
+            }
+            let had_data = remote.has_data();
             match remote.extra {
                 Extra::Client(ref mut client) => {
-                    client.buffer.extend(buf);
-                    if client.buffer.len() == buf.len() {
-                        // Send readable only if it wasn't readable
+                    client.buffer.push(&buf[..len]);
+                    client.pushed += len;
+                    if mode == Mode::Stream {
+                        client.queued += len;
+                    } else {
+                        client.packets.push_back(len);
+                        client.queued += message_cost(len);
+                    }
+                    if mode != Mode::Stream || (len > 0 && !had_data) {
+                        // Send readable for every new message, but
+                        // in a stream only if it wasn't readable
                         // before
-                        self.socket.post_fevent(remote_id, EVENT_READ.bits())?;
+                        self.notify(remote_id, EVENT_READ)?;
                     }
-                    Ok(Some(buf.len()))
+                    Ok(Some(len))
                 },
-                Extra::Listener(_) => panic!("somehow, a client was connected to a listener directly")
+                _ => panic!("somehow, a client was connected to a listener directly")
             }
         } else if client.remote == Connection::Closed {
             Err(Error::new(EPIPE))
+        } else if client.remote == Connection::Reset {
+            Err(Error::new(ECONNRESET))
+        } else if client.remote == Connection::Refused {
+            Err(Error::new(ECONNREFUSED))
         } else if (flags as usize) & O_NONBLOCK == O_NONBLOCK {
             Err(Error::new(EAGAIN))
         } else {
-            Ok(None)
+            self.block(id, EVENT_WRITE)
         }
     }
     fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
-        // Write scheme name
-        const PREFIX: &[u8] = b"chan:";
-        let len = cmp::min(PREFIX.len(), buf.len());
-        buf[..len].copy_from_slice(&PREFIX[..len]);
-        if len < PREFIX.len() {
-            return Ok(Some(len));
-        }
-
-        // Write path
         let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
         let path = handle.path.as_ref().ok_or(Error::new(EBADF))?;
-        let len = cmp::min(path.len(), buf.len() - PREFIX.len());
-        buf[PREFIX.len()..][..len].copy_from_slice(&path.as_bytes()[..len]);
+        Ok(Some(path::fpath(b"chan:", path, buf)))
+    }
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<Option<usize>> {
+        let mut id = id;
+        let mut handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        if let Extra::Stat(listener_id) = handle.extra {
+            id = listener_id;
+            handle = self.handles.get(&id).ok_or(Error::new(ENOENT))?;
+        }
 
-        Ok(Some(PREFIX.len() + len))
+        *stat = Stat {
+            st_ino: id as u64,
+            st_blksize: handle.capacity as u32,
+            ..Stat::default()
+        };
+        // Listeners are the only thing a name leads to, and get a link
+        // count of 1 to tell them apart from connections
+        match handle.extra {
+            Extra::Client(ref client) => {
+                stat.st_mode = MODE_SOCK | 0o600;
+                stat.st_uid = handle.cred.uid;
+                stat.st_gid = handle.cred.gid;
+                stat.st_size = client.buffer.len() as u64;
+            },
+            Extra::Datagram(ref datagram) => {
+                stat.st_mode = MODE_SOCK | handle.perm.mode;
+                stat.st_nlink = datagram.path.is_some() as u32;
+                stat.st_uid = handle.perm.uid;
+                stat.st_gid = handle.perm.gid;
+                stat.st_size = datagram.queued as u64;
+            },
+            Extra::Listener(_) => {
+                stat.st_mode = MODE_SOCK | handle.perm.mode;
+                stat.st_nlink = 1;
+                stat.st_uid = handle.perm.uid;
+                stat.st_gid = handle.perm.gid;
+            },
+            Extra::Directory(ref directory) => {
+                stat.st_mode = MODE_DIR | 0o555;
+                stat.st_nlink = 1;
+                stat.st_size = directory.entries.len() as u64;
+            },
+            Extra::Peek(target_id) => {
+                stat.st_mode = MODE_SOCK | 0o400;
+                stat.st_uid = handle.cred.uid;
+                stat.st_gid = handle.cred.gid;
+                stat.st_size = self.handles.get(&target_id).map_or(0, |target| match target.extra {
+                    Extra::Client(ref client) => client.buffer.len(),
+                    Extra::Datagram(ref datagram) => datagram.queued,
+                    _ => 0
+                }) as u64;
+            },
+            Extra::Stat(_) => unreachable!("stat handle pointing to a stat handle")
+        }
+        Ok(Some(0))
     }
     fn fsync(&mut self, id: usize) -> Result<Option<usize>> {
         self.handles.get(&id)
@@ -277,45 +1240,150 @@ impl SchemeBlockMut for ChanScheme {
             .and(Ok(Some(id)))
     }
     fn read(&mut self, id: usize, buf: &mut [u8], _offset: u64, flags: u32) -> Result<Option<usize>> {
-        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        if let Extra::Peek(target_id) = handle.extra {
+            return self.peek(target_id, buf, flags, handle.timeouts.read);
+        }
+        if self.queued(id, EVENT_READ) {
+            return self.wait(id, EVENT_READ, flags as usize);
+        }
+        let handle = self.handles.get_mut(&id).unwrap();
+        if handle.mode == Mode::Datagram {
+            return self.recv_datagram(id, buf, flags);
+        }
+        let mode = handle.mode;
+        let has_data = handle.has_data();
         let client = handle.require_client()?;
 
-        if !client.buffer.is_empty() {
-            let len = cmp::min(buf.len(), client.buffer.len());
-            buf[..len].copy_from_slice(&client.buffer[..len]);
-            client.buffer.drain(..len);
+        if has_data {
+            let (len, consumed) = client.next_read(mode, buf.len());
+            if mode == Mode::Stream {
+                client.queued -= consumed;
+            } else {
+                client.packets.pop_front();
+                client.queued -= message_cost(consumed);
+            }
+            client.buffer.peek(&mut buf[..len]);
+            client.buffer.consume(consumed);
+            client.popped += consumed;
+            let remote = client.remote;
+            // Every read makes room. Whether it's enough for a held back
+            // packet is up to the writer to find out
+            let held_back = mem::take(&mut client.held_back);
+            if mode != Mode::Stream {
+                handle.truncated = consumed - len;
+            }
+            if held_back && let Connection::Open(remote_id) = remote {
+                // Send writable only if the writer was held back
+                // before
+                self.notify(remote_id, EVENT_WRITE)?;
+            }
             Ok(Some(len))
-        } else if client.remote == Connection::Closed {
-            // Remote dropped, send EOF
+        } else if client.remote == Connection::Reset {
+            // Report the reset once, then behave like a closed stream
+            client.remote = Connection::Closed;
+            Err(Error::new(ECONNRESET))
+        } else if client.eof() {
+            // Remote dropped or stopped writing, send EOF
             Ok(Some(0))
         } else if (flags as usize) & O_NONBLOCK == O_NONBLOCK {
             Err(Error::new(EAGAIN))
         } else {
-            Ok(None)
+            self.block(id, EVENT_READ)
+        }
+    }
+    fn getdents<'buf>(&mut self, id: usize, mut buf: DirentBuf<&'buf mut [u8]>, opaque_offset: u64) -> Result<Option<DirentBuf<&'buf mut [u8]>>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let directory = match handle.extra {
+            Extra::Directory(ref directory) => directory,
+            _ => return Err(Error::new(ENOTDIR))
+        };
+
+        let mut written = 0;
+        for (i, (name, kind)) in directory.entries.iter().enumerate().skip(opaque_offset as usize) {
+            let res = buf.entry(DirEntry {
+                inode: 0,
+                next_opaque_id: i as u64 + 1,
+                name,
+                kind: *kind,
+            });
+            match res {
+                Ok(()) => written += 1,
+                // Out of space, the rest is returned next time
+                Err(_) if written > 0 => break,
+                Err(err) => return Err(err)
+            }
         }
+        Ok(Some(buf))
     }
     fn close(&mut self, id: usize) -> Result<Option<usize>> {
         let handle = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+        // Peeking at this handle, or connecting to it, fails from now on
+        self.wake(id, EVENT_READ | EVENT_WRITE);
 
         match handle.extra {
-            Extra::Client(client) => if let Connection::Open(remote_id) = client.remote {
-                let remote = self.handles.get_mut(&remote_id).unwrap();
+            Extra::Client(client) => {
+                if let Connection::Open(remote_id) = client.remote {
+                    let remote = self.handles.get_mut(&remote_id).unwrap();
+                    let had_data = remote.has_data();
 
-                match remote.extra {
-                    Extra::Client(ref mut client) => {
-                        client.remote = Connection::Closed;
-                        if client.buffer.is_empty() {
-                            // Post readable on EOF only if it wasn't
-                            // readable before
-                            self.socket.post_fevent(remote_id, EVENT_READ.bits())?;
-                        }
-                    },
-                    Extra::Listener(_) => panic!("a client can't be connected to a listener!")
+                    match remote.extra {
+                        Extra::Client(ref mut client) => {
+                            client.remote = Connection::Closed;
+                            if !had_data {
+                                // Post readable on EOF only if it wasn't
+                                // readable before
+                                self.notify(remote_id, EVENT_READ)?;
+                            }
+                            // Writes blocked on a full buffer fail now
+                            self.wake(remote_id, EVENT_WRITE);
+                        },
+                        _ => panic!("a client can't be connected to a listener!")
+                    }
+                }
+                // Nobody is left to receive these
+                for (_, fd) in client.fds {
+                    let _ = syscall::close(fd);
+                }
+                if client.remote == Connection::Waiting
+                    && let Some(listener_id) = client.listener {
+                    // Make room in the backlog of the listener this was
+                    // waiting on
+                    self.wake(listener_id, EVENT_WRITE);
+                } else if let Some(listener_id) = client.listener
+                    && let Some(listener) = self.handles.get_mut(&listener_id)
+                    && let Extra::Listener(ref mut listener) = listener.extra {
+                    // Make room for the next connection to be accepted
+                    listener.accepted -= 1;
+                    if !listener.awaiting.is_empty() {
+                        self.notify(listener_id, EVENT_READ | EVENT_WRITE)?;
+                    }
                 }
             },
-            Extra::Listener(listener) => if let Some(path) = listener.path {
-                self.listeners.remove(&path);
-            }
+            Extra::Listener(listener) => {
+                if let Some(path) = listener.path {
+                    self.listeners.remove(&path);
+                }
+                // Let everyone still waiting know they won't be accepted
+                for client_id in listener.awaiting {
+                    if let Some(client) = self.handles.get_mut(&client_id)
+                        && let Extra::Client(ref mut client) = client.extra {
+                        client.remote = Connection::Refused;
+                        client.listener = None;
+                        self.notify(client_id, EVENT_READ | EVENT_WRITE)?;
+                    }
+                }
+            },
+            Extra::Datagram(datagram) => {
+                if let Some(path) = datagram.path {
+                    self.listeners.remove(&path);
+                }
+                // Writes waiting for space in this one fail now
+                for writer in datagram.writers {
+                    self.wake(writer, EVENT_WRITE);
+                }
+            },
+            Extra::Directory(_) | Extra::Stat(_) | Extra::Peek(_) => ()
         }
         Ok(Some(0))
     }
@@ -327,6 +1395,19 @@ impl SchemeBlockMut for ChanScheme {
     // |  __/ ___ \|  _ <  / ___ \| |  | | |___  | | | |___|  _ < ___) |
     // |_| /_/   \_\_| \_\/_/   \_\_|  |_|_____| |_| |_____|_| \_\____/
 
+    fn fchmod(&mut self, id: usize, mode: u16) -> Result<Option<usize>> {
+        let (cred, handle) = self.owned_handle(id)?;
+        if !handle.perm.owned_by(cred.uid) {
+            return Err(Error::new(EPERM));
+        }
+        handle.perm.mode = mode & 0o777;
+        Ok(Some(0))
+    }
+    fn fchown(&mut self, id: usize, uid: u32, gid: u32) -> Result<Option<usize>> {
+        let (cred, handle) = self.owned_handle(id)?;
+        handle.perm.chown(cred.uid, cred.gid, uid, gid)?;
+        Ok(Some(0))
+    }
     fn fcntl(&mut self, id: usize, cmd: usize, arg: usize) -> Result<Option<usize>> {
         let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
         match cmd {
@@ -335,23 +1416,102 @@ impl SchemeBlockMut for ChanScheme {
                 handle.flags = arg;
                 Ok(Some(0))
             },
+            F_GETPIPE_SZ => Ok(Some(handle.capacity)),
+            F_SETPIPE_SZ => {
+                if arg == 0 {
+                    return Err(Error::new(EINVAL));
+                }
+                let had_space = handle.space() > 0;
+                handle.capacity = arg;
+                if !had_space && handle.space() > 0 && let Extra::Client(ref client) = handle.extra && let Connection::Open(remote_id) = client.remote {
+                    self.notify(remote_id, EVENT_WRITE)?;
+                }
+                Ok(Some(arg))
+            },
+            F_GETSOCKTYPE => Ok(Some(handle.mode.sock_type())),
+            F_SETSOCKTYPE => {
+                // Connections pick their mode up from the listener, so
+                // it can't be changed once any exist
+                handle.set_mode(Mode::from_sock_type(arg)?)?;
+                Ok(Some(0))
+            },
+            F_GETTRUNC => Ok(Some(handle.truncated)),
+            F_GETRCVTIMEO | F_SETRCVTIMEO | F_GETSNDTIMEO | F_SETSNDTIMEO => handle.timeouts.fcntl(cmd, arg).map(Some),
+            F_GETNREAD => match handle.extra {
+                Extra::Peek(target_id) => Ok(Some(self.handles.get(&target_id).map_or(0, Handle::nread))),
+                _ => Ok(Some(handle.nread()))
+            },
+            F_GETBACKLOG => Ok(Some(handle.require_listener()?.backlog)),
+            F_SETBACKLOG => {
+                handle.require_listener()?.backlog = arg;
+                // Connections might fit now
+                self.wake(id, EVENT_WRITE);
+                Ok(Some(0))
+            },
+            F_GETMAXCONN => Ok(Some(handle.require_listener()?.max_accepted)),
+            F_SETMAXCONN => {
+                let listener = handle.require_listener()?;
+                let was_limited = listener.limited();
+                listener.max_accepted = arg;
+                if was_limited && !listener.limited() && !listener.awaiting.is_empty() {
+                    self.notify(id, EVENT_READ | EVENT_WRITE)?;
+                }
+                Ok(Some(0))
+            },
+            F_SHUTDOWN => {
+                self.shutdown(id, arg)?;
+                Ok(Some(0))
+            },
+            F_ABORT => {
+                self.abort(id)?;
+                Ok(Some(0))
+            },
+            F_GETPEERPID | F_GETPEERUID | F_GETPEERGID => {
+                let cred = handle.require_client()?.peer_cred.ok_or(Error::new(ENOTCONN))?;
+                Ok(Some(match cmd {
+                    F_GETPEERPID => cred.pid,
+                    F_GETPEERUID => cred.uid as usize,
+                    _ => cred.gid as usize
+                }))
+            },
             _ => Err(Error::new(EINVAL))
         }
     }
     fn fevent(&mut self, id: usize, _flags: EventFlags) -> Result<Option<EventFlags>> {
-        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
         let mut events = EventFlags::empty();
         match handle.extra {
             Extra::Client(ref client) => {
-                if let Connection::Open(_) = client.remote {
+                if let Connection::Open(remote_id) = client.remote
+                    && !client.shutdown_write
+                    && self.handles.get(&remote_id).is_some_and(|remote| remote.space() > 0) {
                     events |= EVENT_WRITE;
                 }
-                if !client.buffer.is_empty() || client.remote == Connection::Closed {
+                if client.remote == Connection::Refused {
+                    // Both fail right away
+                    events |= EVENT_READ | EVENT_WRITE;
+                }
+                if handle.has_data() || client.fd_ready() || client.shutdown_read
+                    || client.remote == Connection::Closed || client.remote == Connection::Reset {
                     events |= EVENT_READ;
                 }
             },
-            Extra::Listener(ref listener) => if !listener.awaiting.is_empty() {
+            Extra::Listener(ref listener) => if !listener.awaiting.is_empty() && !listener.limited() {
                 events |= EVENT_READ | EVENT_WRITE;
+            },
+            Extra::Directory(_) | Extra::Stat(_) | Extra::Peek(_) => (),
+            Extra::Datagram(ref datagram) => {
+                // Unconnected handles can't know who the next message is
+                // for, and writing to a closed peer fails right away
+                let writable = datagram.peer.is_none_or(|peer| {
+                    self.handles.get(&peer).is_none_or(|remote| remote.space() > 0)
+                });
+                if writable {
+                    events |= EVENT_WRITE;
+                }
+                if !datagram.queue.is_empty() {
+                    events |= EVENT_READ;
+                }
             }
         }
         Ok(Some(events))
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    mem,
+    rc::Rc,
+};
+use syscall::{flag::*, error::*, Error};
+use redox_scheme::{SchemeBlockMut, V2};
+use ipcd::flag::*;
+
+use crate::path;
+use crate::wait::{self, BlockScheme, Blocked, Timeouts};
+
+/// Largest value a counter may have, same as Linux
+pub const MAX_COUNT: u64 = u64::MAX - 1;
+
+/// Length of the counts read and written
+pub const COUNT_LEN: usize = mem::size_of::<u64>();
+
+#[derive(Default)]
+pub struct Counter {
+    /// Name to open it by, if it has one
+    path: Option<Rc<str>>,
+    count: u64,
+    handles: Vec<usize>,
+}
+
+pub struct Handle {
+    flags: usize,
+    /// Also what requests blocked on the counter wait on
+    counter: usize,
+    /// Reads take one off instead of everything
+    semaphore: bool,
+    timeouts: Timeouts,
+}
+
+pub struct EventFdScheme {
+    names: HashMap<Rc<str>, usize>,
+    counters: HashMap<usize, Counter>,
+    handles: HashMap<usize, Handle>,
+    next_id: usize,
+    blocked: Blocked,
+    pub socket: redox_scheme::Socket,
+}
+impl EventFdScheme {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            names: HashMap::new(),
+            counters: HashMap::new(),
+            handles: HashMap::new(),
+            next_id: 0,
+            blocked: Blocked::default(),
+            socket: redox_scheme::Socket::<V2>::nonblock("eventfd")?,
+        })
+    }
+
+    /// Post an event for every handle of a counter and wake up requests
+    /// blocked on it
+    fn notify(&mut self, counter_id: usize, events: EventFlags) -> Result<()> {
+        wait::notify(&self.socket, &mut self.blocked, &self.counters[&counter_id].handles, counter_id, events)
+    }
+
+    /// Block the current request until the counter changes, or fail with
+    /// EAGAIN in non-blocking mode
+    fn wait<T>(&mut self, id: usize, events: EventFlags, flags: usize) -> Result<Option<T>> {
+        if flags & O_NONBLOCK == O_NONBLOCK {
+            return Err(Error::new(EAGAIN));
+        }
+        let handle = &self.handles[&id];
+        self.blocked.block(handle.counter, events, handle.timeouts.get(events))
+    }
+}
+
+impl BlockScheme for EventFdScheme {
+    fn socket(&self) -> &redox_scheme::Socket {
+        &self.socket
+    }
+    fn blocked(&mut self) -> &mut Blocked {
+        &mut self.blocked
+    }
+}
+
+impl SchemeBlockMut for EventFdScheme {
+    fn open(&mut self, path: &str, flags: usize, _uid: u32, _gid: u32) -> Result<Option<usize>> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // Every open of `eventfd:` makes a new counter, named ones are
+        // shared
+        let counter_id = match self.names.get(path) {
+            Some(&counter_id) => counter_id,
+            None => {
+                let counter_id = self.next_id;
+                self.next_id += 1;
+
+                let mut counter = Counter::default();
+                if !path.is_empty() {
+                    let path = Rc::from(path);
+                    self.names.insert(Rc::clone(&path), counter_id);
+                    counter.path = Some(path);
+                }
+                self.counters.insert(counter_id, counter);
+                counter_id
+            }
+        };
+        self.counters.get_mut(&counter_id).unwrap().handles.push(id);
+
+        self.handles.insert(id, Handle {
+            flags,
+            counter: counter_id,
+            semaphore: false,
+            timeouts: Timeouts::default(),
+        });
+        Ok(Some(id))
+    }
+    fn dup(&mut self, old_id: usize, buf: &[u8]) -> Result<Option<usize>> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        let handle = self.handles.get(&old_id).ok_or(Error::new(EBADF))?;
+        let new = Handle {
+            flags: handle.flags,
+            counter: handle.counter,
+            semaphore: handle.semaphore,
+            timeouts: handle.timeouts,
+        };
+        let id = self.next_id;
+        self.counters.get_mut(&new.counter).expect("handle pointing to nothing").handles.push(id);
+        self.handles.insert(id, new);
+        self.next_id += 1;
+        Ok(Some(id))
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8], _offset: u64, flags: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let counter_id = handle.counter;
+        let counter = self.counters.get_mut(&counter_id).expect("handle pointing to nothing");
+
+        if buf.len() < COUNT_LEN {
+            return Err(Error::new(EINVAL));
+        }
+        let mut value = [0; COUNT_LEN];
+        value.copy_from_slice(&buf[..COUNT_LEN]);
+        let value = u64::from_ne_bytes(value);
+        if value == u64::MAX {
+            return Err(Error::new(EINVAL));
+        }
+
+        // Wait until a read makes room, rather than overflowing
+        if value > MAX_COUNT - counter.count || self.blocked.queued(counter_id, EVENT_WRITE) {
+            return self.wait(id, EVENT_WRITE, flags as usize);
+        }
+        counter.count += value;
+        if counter.count > 0 {
+            self.notify(counter_id, EVENT_READ)?;
+        }
+
+        Ok(Some(COUNT_LEN))
+    }
+    fn read(&mut self, id: usize, buf: &mut [u8], _offset: u64, flags: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let counter_id = handle.counter;
+        let semaphore = handle.semaphore;
+        let counter = self.counters.get_mut(&counter_id).expect("handle pointing to nothing");
+
+        if buf.len() < COUNT_LEN {
+            return Err(Error::new(EINVAL));
+        }
+        if counter.count == 0 || self.blocked.queued(counter_id, EVENT_READ) {
+            return self.wait(id, EVENT_READ, flags as usize);
+        }
+
+        let value = if semaphore { 1 } else { counter.count };
+        counter.count -= value;
+        buf[..COUNT_LEN].copy_from_slice(&value.to_ne_bytes());
+        self.notify(counter_id, EVENT_WRITE)?;
+
+        Ok(Some(COUNT_LEN))
+    }
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let path = self.counters[&handle.counter].path.as_deref().unwrap_or("");
+        Ok(Some(path::fpath(b"eventfd:", path, buf)))
+    }
+    fn fcntl(&mut self, id: usize, cmd: usize, arg: usize) -> Result<Option<usize>> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        match cmd {
+            F_GETFL => Ok(Some(handle.flags)),
+            F_SETFL => {
+                handle.flags = arg;
+                Ok(Some(0))
+            },
+            F_GETSEMAPHORE => Ok(Some(handle.semaphore as usize)),
+            F_SETSEMAPHORE => {
+                handle.semaphore = arg != 0;
+                Ok(Some(0))
+            },
+            F_GETRCVTIMEO | F_SETRCVTIMEO | F_GETSNDTIMEO | F_SETSNDTIMEO => handle.timeouts.fcntl(cmd, arg).map(Some),
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+    fn fevent(&mut self, id: usize, _flags: EventFlags) -> Result<Option<EventFlags>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let counter = &self.counters[&handle.counter];
+        let mut events = EventFlags::empty();
+        if counter.count > 0 {
+            events |= EVENT_READ;
+        }
+        if counter.count < MAX_COUNT {
+            events |= EVENT_WRITE;
+        }
+        Ok(Some(events))
+    }
+    fn close(&mut self, id: usize) -> Result<Option<usize>> {
+        let handle = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+        let counter = self.counters.get_mut(&handle.counter).expect("handle pointing to nothing");
+        counter.handles.retain(|&handle_id| handle_id != id);
+        if counter.handles.is_empty() {
+            // Nobody uses this counter anymore, forget it
+            if let Some(ref path) = counter.path {
+                self.names.remove(path);
+            }
+            self.counters.remove(&handle.counter);
+        }
+        Ok(Some(0))
+    }
+}
@@ -0,0 +1,71 @@
+//! fcntl commands and their arguments that ipcd understands, missing from
+//! the syscall crate
+
+// Same numbers as the Linux pipe resizing commands
+pub const F_SETPIPE_SZ: usize = 1031;
+pub const F_GETPIPE_SZ: usize = 1032;
+
+// chan
+pub const F_GETSOCKTYPE: usize = 0x1000;
+pub const F_SETSOCKTYPE: usize = 0x1001;
+pub const F_GETTRUNC: usize = 0x1002;
+pub const F_GETPEERPID: usize = 0x1003;
+pub const F_GETPEERUID: usize = 0x1004;
+pub const F_GETPEERGID: usize = 0x1005;
+pub const F_SHUTDOWN: usize = 0x1006;
+pub const F_ABORT: usize = 0x1007;
+
+pub const F_GETBACKLOG: usize = 0x1008;
+pub const F_SETBACKLOG: usize = 0x1009;
+pub const F_GETMAXCONN: usize = 0x100A;
+pub const F_SETMAXCONN: usize = 0x100B;
+/// What relibc's `ioctl(FIONREAD)` maps to
+pub const F_GETNREAD: usize = 0x100C;
+// Like SO_RCVTIMEO and SO_SNDTIMEO, in milliseconds. mq and eventfd take
+// these as well, sem and lock only the receive ones
+pub const F_GETRCVTIMEO: usize = 0x100D;
+pub const F_SETRCVTIMEO: usize = 0x100E;
+pub const F_GETSNDTIMEO: usize = 0x100F;
+pub const F_SETSNDTIMEO: usize = 0x1010;
+
+// Directions passed to F_SHUTDOWN, same numbers as POSIX
+pub const SHUT_RD: usize = 0;
+pub const SHUT_WR: usize = 1;
+pub const SHUT_RDWR: usize = 2;
+
+// Socket types, same numbers as Linux
+pub const SOCK_STREAM: usize = 1;
+pub const SOCK_DGRAM: usize = 2;
+pub const SOCK_SEQPACKET: usize = 5;
+
+// topic
+pub const F_GETPOLICY: usize = 0x1100;
+pub const F_SETPOLICY: usize = 0x1101;
+pub const F_GETDROPPED: usize = 0x1102;
+
+// What a subscriber does once its queue is full, passed to F_SETPOLICY
+pub const POLICY_BLOCK: usize = 0;
+pub const POLICY_DROP_OLDEST: usize = 1;
+pub const POLICY_DISCONNECT: usize = 2;
+
+// mq
+pub const F_GETMAXMSG: usize = 0x1200;
+pub const F_GETMSGSIZE: usize = 0x1201;
+pub const F_GETCURMSGS: usize = 0x1202;
+
+// sem
+pub const F_GETVALUE: usize = 0x1300;
+
+// lock
+pub const F_FLOCK: usize = 0x1400;
+pub const F_GETFLOCK: usize = 0x1401;
+
+// Operations passed to F_FLOCK, same numbers as flock
+pub const LOCK_SH: usize = 1;
+pub const LOCK_EX: usize = 2;
+pub const LOCK_NB: usize = 4;
+pub const LOCK_UN: usize = 8;
+
+// eventfd
+pub const F_GETSEMAPHORE: usize = 0x1500;
+pub const F_SETSEMAPHORE: usize = 0x1501;
@@ -0,0 +1,5 @@
+//! Client side of what ipcd serves, for programs that need more than
+//! reading and writing its handles
+
+pub mod flag;
+pub mod ringbuf;
@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    rc::Rc,
+};
+use syscall::{flag::*, error::*, Error};
+use redox_scheme::{SchemeBlockMut, V2};
+use ipcd::flag::*;
+
+use crate::path;
+use crate::perm::{Perm, READ, WRITE};
+use crate::wait::{self, BlockScheme, Blocked, Timeouts};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hold {
+    None,
+    Shared,
+    Exclusive,
+}
+
+pub struct Lock {
+    /// Number of handles holding it shared
+    shared: usize,
+    /// The handle holding it exclusively
+    exclusive: Option<usize>,
+    /// Owner and permissions, from whoever opened it first
+    perm: Perm,
+    handles: Vec<usize>,
+}
+impl Lock {
+    /// Return true if a handle holding nothing could get the lock now
+    pub fn available(&self, hold: Hold) -> bool {
+        match hold {
+            Hold::None => true,
+            Hold::Shared => self.exclusive.is_none(),
+            Hold::Exclusive => self.exclusive.is_none() && self.shared == 0
+        }
+    }
+}
+
+pub struct Handle {
+    flags: usize,
+    path: Rc<str>,
+    /// Also what requests for the lock wait on
+    lock: usize,
+    hold: Hold,
+    read: bool,
+    write: bool,
+    timeouts: Timeouts,
+}
+
+pub struct LockScheme {
+    names: HashMap<Rc<str>, usize>,
+    locks: HashMap<usize, Lock>,
+    handles: HashMap<usize, Handle>,
+    next_id: usize,
+    blocked: Blocked,
+    pub socket: redox_scheme::Socket,
+}
+impl LockScheme {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            names: HashMap::new(),
+            locks: HashMap::new(),
+            handles: HashMap::new(),
+            next_id: 0,
+            blocked: Blocked::default(),
+            socket: redox_scheme::Socket::<V2>::nonblock("lock")?,
+        })
+    }
+
+    /// Post an event for every handle of a lock and wake up requests
+    /// blocked on it
+    fn notify(&mut self, lock_id: usize, events: EventFlags) -> Result<()> {
+        wait::notify(&self.socket, &mut self.blocked, &self.locks[&lock_id].handles, lock_id, events)
+    }
+
+    /// Give up whatever the handle holds, and let the next ones in line try
+    fn release(&mut self, id: usize) -> Result<()> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let lock_id = handle.lock;
+        let lock = self.locks.get_mut(&lock_id).expect("handle pointing to nothing");
+        match handle.hold {
+            Hold::None => return Ok(()),
+            Hold::Shared => lock.shared -= 1,
+            Hold::Exclusive => lock.exclusive = None
+        }
+        handle.hold = Hold::None;
+        self.notify(lock_id, EVENT_READ | EVENT_WRITE)
+    }
+
+    /// Take the lock shared or exclusively, waiting behind everyone who
+    /// asked first
+    fn acquire(&mut self, id: usize, hold: Hold, flags: usize) -> Result<Option<usize>> {
+        // Like flock, converting a lock gives up the old one first
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        // A shared lock keeps writers out and an exclusive one keeps
+        // everyone out, so each needs the matching access
+        match hold {
+            Hold::Shared if !handle.read => return Err(Error::new(EBADF)),
+            Hold::Exclusive if !handle.write => return Err(Error::new(EBADF)),
+            _ => ()
+        }
+        let current = handle.hold;
+        if current == hold {
+            return Ok(Some(0));
+        }
+        self.release(id)?;
+
+        let handle = self.handles.get_mut(&id).expect("handle gone while locking");
+        let lock_id = handle.lock;
+        let lock = self.locks.get_mut(&lock_id).expect("handle pointing to nothing");
+        if !lock.available(hold) || self.blocked.queued(lock_id, EVENT_READ) {
+            return if flags & LOCK_NB == LOCK_NB || handle.flags & O_NONBLOCK == O_NONBLOCK {
+                Err(Error::new(EWOULDBLOCK))
+            } else {
+                self.blocked.block(lock_id, EVENT_READ, handle.timeouts.read)
+            };
+        }
+
+        match hold {
+            Hold::None => (),
+            Hold::Shared => lock.shared += 1,
+            Hold::Exclusive => lock.exclusive = Some(id)
+        }
+        handle.hold = hold;
+        Ok(Some(0))
+    }
+}
+
+impl BlockScheme for LockScheme {
+    fn socket(&self) -> &redox_scheme::Socket {
+        &self.socket
+    }
+    fn blocked(&mut self) -> &mut Blocked {
+        &mut self.blocked
+    }
+}
+
+impl SchemeBlockMut for LockScheme {
+    fn open(&mut self, path: &str, flags: usize, uid: u32, gid: u32) -> Result<Option<usize>> {
+        if path.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+        let (read, write) = match flags & O_ACCMODE {
+            O_RDONLY => (true, false),
+            O_WRONLY => (false, true),
+            _ => (true, true)
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let lock_id = match self.names.get(path) {
+            Some(&lock_id) => {
+                let lock = self.locks.get_mut(&lock_id).expect("name pointing to nothing");
+                let access = if read { READ } else { 0 } | if write { WRITE } else { 0 };
+                if !lock.perm.allows(uid, gid, access) {
+                    return Err(Error::new(EACCES));
+                }
+                lock.handles.push(id);
+                lock_id
+            },
+            None => {
+                let lock_id = self.next_id;
+                self.next_id += 1;
+                self.names.insert(Rc::from(path), lock_id);
+                self.locks.insert(lock_id, Lock {
+                    shared: 0,
+                    exclusive: None,
+                    perm: Perm::new(uid, gid, flags),
+                    handles: vec![id],
+                });
+                lock_id
+            }
+        };
+
+        self.handles.insert(id, Handle {
+            flags,
+            path: Rc::from(path),
+            lock: lock_id,
+            hold: Hold::None,
+            read,
+            write,
+            timeouts: Timeouts::default(),
+        });
+        Ok(Some(id))
+    }
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        Ok(Some(path::fpath(b"lock:", &handle.path, buf)))
+    }
+    fn fcntl(&mut self, id: usize, cmd: usize, arg: usize) -> Result<Option<usize>> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        match cmd {
+            F_GETFL => Ok(Some(handle.flags)),
+            F_SETFL => {
+                handle.flags = arg;
+                Ok(Some(0))
+            },
+            F_FLOCK => match arg & !LOCK_NB {
+                LOCK_SH => self.acquire(id, Hold::Shared, arg),
+                LOCK_EX => self.acquire(id, Hold::Exclusive, arg),
+                LOCK_UN => {
+                    self.release(id)?;
+                    Ok(Some(0))
+                },
+                _ => Err(Error::new(EINVAL))
+            },
+            F_GETFLOCK => Ok(Some(match handle.hold {
+                Hold::None => 0,
+                Hold::Shared => LOCK_SH,
+                Hold::Exclusive => LOCK_EX
+            })),
+            F_GETRCVTIMEO | F_SETRCVTIMEO => handle.timeouts.fcntl(cmd, arg).map(Some),
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+    fn fevent(&mut self, id: usize, _flags: EventFlags) -> Result<Option<EventFlags>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let lock = &self.locks[&handle.lock];
+        let mut events = EventFlags::empty();
+        if !self.blocked.queued(handle.lock, EVENT_READ) {
+            if lock.available(Hold::Shared) {
+                events |= EVENT_READ;
+            }
+            if lock.available(Hold::Exclusive) {
+                events |= EVENT_WRITE;
+            }
+        }
+        Ok(Some(events))
+    }
+    fn close(&mut self, id: usize) -> Result<Option<usize>> {
+        // Whatever the handle held goes with it, even if its process died
+        self.release(id)?;
+
+        let handle = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+        let lock = self.locks.get_mut(&handle.lock).expect("handle pointing to nothing");
+        lock.handles.retain(|&handle_id| handle_id != id);
+        if lock.handles.is_empty() {
+            // Nobody uses this lock anymore, forget it
+            self.locks.remove(&handle.lock);
+            self.names.remove(&handle.path);
+        }
+        Ok(Some(0))
+    }
+}
@@ -1,15 +1,30 @@
-#![feature(int_roundings, let_chains)]
-
-use std::collections::VecDeque;
 use event::{EventQueue, EventFlags};
-use redox_scheme::{CallRequest, RequestKind, Response, SignalBehavior};
-use syscall::{Error, Result, EAGAIN, EWOULDBLOCK, ENODEV, EINTR};
+use syscall::Result;
 
+mod buffer;
 mod chan;
+mod eventfd;
+mod lock;
+mod mq;
+mod path;
+mod perm;
+mod ring;
+mod sem;
 mod shm;
+mod timer;
+mod topic;
+mod wait;
 
 use self::chan::ChanScheme;
+use self::eventfd::EventFdScheme;
+use self::lock::LockScheme;
+use self::mq::MqScheme;
+use self::ring::RingScheme;
+use self::sem::SemScheme;
 use self::shm::ShmScheme;
+use self::timer::Timer;
+use self::topic::TopicScheme;
+use self::wait::Driver;
 
 fn main() {
     redox_daemon::Daemon::new(move |daemon| {
@@ -29,130 +44,81 @@ fn inner(daemon: redox_daemon::Daemon) -> Result<()> {
         enum EventSource {
             ChanSocket,
             ShmSocket,
+            TopicSocket,
+            MqSocket,
+            SemSocket,
+            LockSocket,
+            EventFdSocket,
+            RingSocket,
+            Timer,
         }
     }
     let chan = ChanScheme::new()?;
     let shm = ShmScheme::new()?;
+    let topic = TopicScheme::new()?;
+    let mq = MqScheme::new()?;
+    let sem = SemScheme::new()?;
+    let lock = LockScheme::new()?;
+    let eventfd = EventFdScheme::new()?;
+    let ring = RingScheme::new()?;
+    let mut timer = Timer::new()?;
     daemon.ready().unwrap();
 
-    // Create event listener for both files
+    // Create event listener for all files
     let mut event_queue = EventQueue::<EventSource>::new()?;
 
     event_queue.subscribe(chan.socket.inner().raw(), EventSource::ChanSocket, EventFlags::READ)?;
     event_queue.subscribe(shm.socket.inner().raw(), EventSource::ShmSocket, EventFlags::READ)?;
-
-    struct Todo {
-        req: Option<CallRequest>,
-        canceling: bool,
-    }
-    let mut todo = VecDeque::<Todo>::with_capacity(16);
+    event_queue.subscribe(topic.socket.inner().raw(), EventSource::TopicSocket, EventFlags::READ)?;
+    event_queue.subscribe(mq.socket.inner().raw(), EventSource::MqSocket, EventFlags::READ)?;
+    event_queue.subscribe(sem.socket.inner().raw(), EventSource::SemSocket, EventFlags::READ)?;
+    event_queue.subscribe(lock.socket.inner().raw(), EventSource::LockSocket, EventFlags::READ)?;
+    event_queue.subscribe(eventfd.socket.inner().raw(), EventSource::EventFdSocket, EventFlags::READ)?;
+    event_queue.subscribe(ring.socket.inner().raw(), EventSource::RingSocket, EventFlags::READ)?;
+    event_queue.subscribe(timer.fd, EventSource::Timer, EventFlags::READ)?;
 
     libredox::call::setrens(0, 0)?;
 
-    let mut chan_opt = Some(chan);
-    let mut shm_opt = Some(shm);
-    while chan_opt.is_some() || shm_opt.is_some() {
+    let mut chan = Driver::new(chan);
+    let mut shm = Driver::new(shm);
+    let mut topic = Driver::new(topic);
+    let mut mq = Driver::new(mq);
+    let mut sem = Driver::new(sem);
+    let mut lock = Driver::new(lock);
+    let mut eventfd = Driver::new(eventfd);
+    let mut ring = Driver::new(ring);
+    while chan.is_mounted() || shm.is_mounted() || topic.is_mounted() || mq.is_mounted()
+        || sem.is_mounted() || lock.is_mounted() || eventfd.is_mounted() || ring.is_mounted()
+    {
         let Some(event_res) = event_queue.next() else {
             break;
         };
         let event = event_res?;
 
         match event.user_data {
-            EventSource::ChanSocket => {
-                let mut error: Option<Error> = None;
-
-                let unmount = if let Some(ref mut chan) = chan_opt {
-                    let eof = loop {
-                        match chan.socket.next_request(SignalBehavior::Restart) {
-                            Ok(None) => break true,
-                            Ok(Some(request)) => match request.kind() {
-                                RequestKind::Call(request) => todo.push_front(Todo { req: Some(request), canceling: false }),
-                                RequestKind::Cancellation(request) => {
-                                    if let Some(affected_packet) = todo.iter_mut().find(|t| t.req.as_ref().map_or(false, |r| r.request().request_id() == request.id)) {
-                                        affected_packet.canceling = true;
-                                    }
-                                }
-                                _ => (),
-                            }
-                            Err(Error { errno: EAGAIN | EWOULDBLOCK }) => break false,
-                            Err(error) => return Err(error),
-                        }
-                    };
-
-                    // Process queue, delete finished items
-                    todo.retain_mut(|slot| {
-                        let req = slot.req.take().unwrap();
-
-                        match req.handle_scheme_block_mut(chan) {
-                            Some(res) => {
-                                if let Err(err) = chan.socket.write_response(res, SignalBehavior::Restart) {
-                                    error = Some(err);
-                                }
-                                false
-                            }
-                            None if slot.canceling => {
-                                if let Err(err) = chan.socket.write_response(Response::new(&req, Err(Error::new(EINTR))), SignalBehavior::Restart) {
-                                    error = Some(err);
-                                }
-                                false
-                            }
-                            None => {
-                                slot.req = Some(req);
-                                true
-                            }
-                        }
-                    });
-
-                    eof
-                } else {
-                    false
-                };
-
-                if unmount && let Some(chan) = chan_opt.take() {
-                    for slot in todo.drain(..) {
-                        let res = if slot.canceling {
-                            Err(Error::new(EINTR))
-                        } else {
-                            Err(Error::new(ENODEV))
-                        };
-                        if let Err(err) = chan.socket.write_response(Response::new(&slot.req.unwrap(), res), SignalBehavior::Restart) {
-                            error = Some(err);
-                        }
-                    }
-                }
-
-                if let Some(err) = error {
-                    return Err(err);
-                }
-            },
-            EventSource::ShmSocket => {
-                let unmount = if let Some(ref mut shm) = shm_opt {
-                    let eof = loop {
-                        match shm.socket.next_request(SignalBehavior::Restart) {
-                            Ok(None) => break true,
-                            Ok(Some(request)) => match request.kind() {
-                                RequestKind::Call(request) => {
-                                    let response = request.handle_scheme_mut(shm);
-                                    shm.socket.write_response(response, SignalBehavior::Restart)?;
-                                }
-                                _ => (),
-                            },
-                            Err(Error { errno: EAGAIN | EWOULDBLOCK }) => break false,
-                            Err(err) => return Err(err),
-                        };
-                    };
-
-                    eof
-                } else {
-                    false
-                };
-
-                if unmount {
-                    shm_opt.take();
-                }
+            EventSource::ChanSocket => chan.on_socket(&mut timer)?,
+            EventSource::ShmSocket => shm.on_socket(&mut timer)?,
+            EventSource::TopicSocket => topic.on_socket(&mut timer)?,
+            EventSource::MqSocket => mq.on_socket(&mut timer)?,
+            EventSource::SemSocket => sem.on_socket(&mut timer)?,
+            EventSource::LockSocket => lock.on_socket(&mut timer)?,
+            EventSource::EventFdSocket => eventfd.on_socket(&mut timer)?,
+            EventSource::RingSocket => ring.on_socket(&mut timer)?,
+            EventSource::Timer => {
+                let now = timer.now()?;
+                timer.fired(now);
+                chan.on_timer(&mut timer, now)?;
+                shm.on_timer(&mut timer, now)?;
+                topic.on_timer(&mut timer, now)?;
+                mq.on_timer(&mut timer, now)?;
+                sem.on_timer(&mut timer, now)?;
+                lock.on_timer(&mut timer, now)?;
+                eventfd.on_timer(&mut timer, now)?;
+                ring.on_timer(&mut timer, now)?;
             }
         }
     }
 
     Ok(())
 }
+
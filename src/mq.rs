@@ -0,0 +1,297 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    mem,
+    rc::Rc,
+};
+use syscall::{flag::*, error::*, Error};
+use redox_scheme::{SchemeBlockMut, V2};
+use ipcd::flag::*;
+
+use crate::path;
+use crate::perm::{Perm, READ, WRITE};
+use crate::wait::{self, BlockScheme, Blocked, Timeouts};
+
+// Attributes of queues created without any, same as Linux
+pub const DEFAULT_MAXMSG: usize = 10;
+pub const DEFAULT_MSGSIZE: usize = 8192;
+
+// Largest attributes a queue may be given, same as Linux
+pub const MAX_MAXMSG: usize = 65536;
+pub const MAX_MSGSIZE: usize = 16 * 1024 * 1024;
+
+/// Priorities have to be lower than this, same as Linux
+pub const MQ_PRIO_MAX: usize = 32768;
+
+/// Length of the priority in front of every message
+pub const PRIO_LEN: usize = mem::size_of::<usize>();
+
+pub struct Queue {
+    /// Name to open it by, until it's unlinked
+    path: Rc<str>,
+    linked: bool,
+    /// Messages by priority, each in the order they were sent
+    messages: BTreeMap<usize, VecDeque<Box<[u8]>>>,
+    count: usize,
+    maxmsg: usize,
+    msgsize: usize,
+    /// Owner and permissions, checked when opening
+    perm: Perm,
+    handles: Vec<usize>,
+}
+impl Queue {
+    /// Parse the attributes given when creating a queue, as
+    /// `<maxmsg>,<msgsize>`
+    pub fn parse_attr(attr: &str) -> Result<(usize, usize)> {
+        let (maxmsg, msgsize) = attr.split_once(',').ok_or(Error::new(EINVAL))?;
+        let maxmsg = maxmsg.parse().map_err(|_| Error::new(EINVAL))?;
+        let msgsize = msgsize.parse().map_err(|_| Error::new(EINVAL))?;
+        if !(1..=MAX_MAXMSG).contains(&maxmsg) || !(1..=MAX_MSGSIZE).contains(&msgsize) {
+            return Err(Error::new(EINVAL));
+        }
+        Ok((maxmsg, msgsize))
+    }
+
+    /// Take out the oldest message of the highest priority
+    pub fn pop(&mut self) -> Option<(usize, Box<[u8]>)> {
+        let mut entry = self.messages.last_entry()?;
+        let prio = *entry.key();
+        let message = entry.get_mut().pop_front().expect("empty priority left over");
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+        self.count -= 1;
+        Some((prio, message))
+    }
+}
+
+pub struct Handle {
+    flags: usize,
+    /// Also what requests blocked on the queue wait on
+    queue: usize,
+    receive: bool,
+    send: bool,
+    timeouts: Timeouts,
+}
+
+pub struct MqScheme {
+    names: HashMap<Rc<str>, usize>,
+    queues: HashMap<usize, Queue>,
+    handles: HashMap<usize, Handle>,
+    next_id: usize,
+    blocked: Blocked,
+    pub socket: redox_scheme::Socket,
+}
+impl MqScheme {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            names: HashMap::new(),
+            queues: HashMap::new(),
+            handles: HashMap::new(),
+            next_id: 0,
+            blocked: Blocked::default(),
+            socket: redox_scheme::Socket::<V2>::nonblock("mq")?,
+        })
+    }
+
+    /// Post an event for every handle of a queue and wake up requests
+    /// blocked on it
+    fn notify(&mut self, queue_id: usize, events: EventFlags) -> Result<()> {
+        wait::notify(&self.socket, &mut self.blocked, &self.queues[&queue_id].handles, queue_id, events)
+    }
+
+    /// Block the current request until the queue changes, or fail with
+    /// EAGAIN in non-blocking mode
+    fn wait<T>(&mut self, id: usize, events: EventFlags, flags: usize) -> Result<Option<T>> {
+        if flags & O_NONBLOCK == O_NONBLOCK {
+            return Err(Error::new(EAGAIN));
+        }
+        let handle = &self.handles[&id];
+        self.blocked.block(handle.queue, events, handle.timeouts.get(events))
+    }
+}
+
+impl BlockScheme for MqScheme {
+    fn socket(&self) -> &redox_scheme::Socket {
+        &self.socket
+    }
+    fn blocked(&mut self) -> &mut Blocked {
+        &mut self.blocked
+    }
+}
+
+impl SchemeBlockMut for MqScheme {
+    fn open(&mut self, path: &str, flags: usize, uid: u32, gid: u32) -> Result<Option<usize>> {
+        // Like the attributes passed to mq_open, these only apply to a
+        // queue that is created by this open
+        let (path, attr) = match path.rsplit_once('?') {
+            Some((path, attr)) if flags & O_CREAT == O_CREAT => (path, Some(Queue::parse_attr(attr)?)),
+            _ => (path, None)
+        };
+        if path.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        let (receive, send) = match flags & O_ACCMODE {
+            O_RDONLY => (true, false),
+            O_WRONLY => (false, true),
+            _ => (true, true)
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let queue_id = match self.names.get(path) {
+            Some(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => return Err(Error::new(EEXIST)),
+            Some(&queue_id) => {
+                let queue = self.queues.get_mut(&queue_id).expect("name pointing to nothing");
+                let access = if receive { READ } else { 0 } | if send { WRITE } else { 0 };
+                if !queue.perm.allows(uid, gid, access) {
+                    return Err(Error::new(EACCES));
+                }
+                queue.handles.push(id);
+                queue_id
+            },
+            None if flags & O_CREAT == O_CREAT => {
+                let queue_id = self.next_id;
+                self.next_id += 1;
+
+                let (maxmsg, msgsize) = attr.unwrap_or((DEFAULT_MAXMSG, DEFAULT_MSGSIZE));
+                let path = Rc::from(path);
+                self.names.insert(Rc::clone(&path), queue_id);
+                self.queues.insert(queue_id, Queue {
+                    path,
+                    linked: true,
+                    messages: BTreeMap::new(),
+                    count: 0,
+                    maxmsg,
+                    msgsize,
+                    perm: Perm::new(uid, gid, flags),
+                    handles: vec![id],
+                });
+                queue_id
+            },
+            None => return Err(Error::new(ENOENT))
+        };
+
+        self.handles.insert(id, Handle {
+            flags,
+            queue: queue_id,
+            receive,
+            send,
+            timeouts: Timeouts::default(),
+        });
+        Ok(Some(id))
+    }
+    fn unlink(&mut self, path: &str, uid: u32, _gid: u32) -> Result<Option<usize>> {
+        let &queue_id = self.names.get(path).ok_or(Error::new(ENOENT))?;
+        let queue = self.queues.get_mut(&queue_id).expect("name pointing to nothing");
+        if !queue.perm.owned_by(uid) {
+            return Err(Error::new(EACCES));
+        }
+        self.names.remove(path);
+        queue.linked = false;
+        if queue.handles.is_empty() {
+            self.queues.remove(&queue_id);
+        }
+        Ok(Some(0))
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8], _offset: u64, flags: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        if !handle.send {
+            return Err(Error::new(EBADF));
+        }
+        let queue_id = handle.queue;
+        let queue = self.queues.get_mut(&queue_id).expect("handle pointing to nothing");
+
+        // Every message starts with its priority
+        if buf.len() < PRIO_LEN {
+            return Err(Error::new(EINVAL));
+        }
+        let mut prio = [0; PRIO_LEN];
+        prio.copy_from_slice(&buf[..PRIO_LEN]);
+        let prio = usize::from_ne_bytes(prio);
+        let payload = &buf[PRIO_LEN..];
+        if prio >= MQ_PRIO_MAX {
+            return Err(Error::new(EINVAL));
+        }
+        if payload.len() > queue.msgsize {
+            return Err(Error::new(EMSGSIZE));
+        }
+
+        if queue.count >= queue.maxmsg || self.blocked.queued(queue_id, EVENT_WRITE) {
+            return self.wait(id, EVENT_WRITE, flags as usize);
+        }
+        queue.messages.entry(prio).or_default().push_back(Box::from(payload));
+        queue.count += 1;
+        self.notify(queue_id, EVENT_READ)?;
+
+        Ok(Some(buf.len()))
+    }
+    fn read(&mut self, id: usize, buf: &mut [u8], _offset: u64, flags: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        if !handle.receive {
+            return Err(Error::new(EBADF));
+        }
+        let queue_id = handle.queue;
+        let queue = self.queues.get_mut(&queue_id).expect("handle pointing to nothing");
+
+        // Like mq_receive, refuse buffers that couldn't hold every message
+        if buf.len() < PRIO_LEN + queue.msgsize {
+            return Err(Error::new(EMSGSIZE));
+        }
+        if self.blocked.queued(queue_id, EVENT_READ) {
+            return self.wait(id, EVENT_READ, flags as usize);
+        }
+        let (prio, message) = match queue.pop() {
+            Some(next) => next,
+            None => return self.wait(id, EVENT_READ, flags as usize)
+        };
+
+        buf[..PRIO_LEN].copy_from_slice(&prio.to_ne_bytes());
+        buf[PRIO_LEN..][..message.len()].copy_from_slice(&message);
+        self.notify(queue_id, EVENT_WRITE)?;
+
+        Ok(Some(PRIO_LEN + message.len()))
+    }
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        Ok(Some(path::fpath(b"mq:", &self.queues[&handle.queue].path, buf)))
+    }
+    fn fcntl(&mut self, id: usize, cmd: usize, arg: usize) -> Result<Option<usize>> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let queue = &self.queues[&handle.queue];
+        match cmd {
+            F_GETFL => Ok(Some(handle.flags)),
+            F_SETFL => {
+                handle.flags = arg;
+                Ok(Some(0))
+            },
+            F_GETMAXMSG => Ok(Some(queue.maxmsg)),
+            F_GETMSGSIZE => Ok(Some(queue.msgsize)),
+            F_GETCURMSGS => Ok(Some(queue.count)),
+            F_GETRCVTIMEO | F_SETRCVTIMEO | F_GETSNDTIMEO | F_SETSNDTIMEO => handle.timeouts.fcntl(cmd, arg).map(Some),
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+    fn fevent(&mut self, id: usize, _flags: EventFlags) -> Result<Option<EventFlags>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let queue = &self.queues[&handle.queue];
+        let mut events = EventFlags::empty();
+        if handle.receive && queue.count > 0 {
+            events |= EVENT_READ;
+        }
+        if handle.send && queue.count < queue.maxmsg {
+            events |= EVENT_WRITE;
+        }
+        Ok(Some(events))
+    }
+    fn close(&mut self, id: usize) -> Result<Option<usize>> {
+        let handle = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+        let queue = self.queues.get_mut(&handle.queue).expect("handle pointing to nothing");
+        queue.handles.retain(|&handle_id| handle_id != id);
+        if queue.handles.is_empty() && !queue.linked {
+            // Unlinked and nobody has it open anymore
+            self.queues.remove(&handle.queue);
+        }
+        Ok(Some(0))
+    }
+}
@@ -0,0 +1,18 @@
+use std::cmp;
+
+/// Write `<scheme>:<path>` to `buf` for fpath, cut off if it doesn't fit,
+/// and return how much was written
+pub fn fpath(scheme: &[u8], path: &str, buf: &mut [u8]) -> usize {
+    // Write scheme name
+    let len = cmp::min(scheme.len(), buf.len());
+    buf[..len].copy_from_slice(&scheme[..len]);
+    if len < scheme.len() {
+        return len;
+    }
+
+    // Write path
+    let len = cmp::min(path.len(), buf.len() - scheme.len());
+    buf[scheme.len()..][..len].copy_from_slice(&path.as_bytes()[..len]);
+
+    scheme.len() + len
+}
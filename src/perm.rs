@@ -0,0 +1,70 @@
+use syscall::{error::*, Error};
+
+// What a user asks to do, as in the bits of a mode
+pub const READ: u16 = 0o4;
+pub const WRITE: u16 = 0o2;
+
+/// Owner and permissions of something reachable by name, like the
+/// `ipc_perm` of System V IPC
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Perm {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+}
+impl Perm {
+    /// Owned by whoever creates something with `open`, which passes the
+    /// mode in the lower bits of the flags
+    pub fn new(uid: u32, gid: u32, flags: usize) -> Self {
+        Self {
+            uid,
+            gid,
+            mode: (flags & 0o777) as u16,
+        }
+    }
+
+    /// Return true if the user may do everything `access` asks for, some
+    /// of `READ` and `WRITE`. Root may do anything
+    pub fn allows(&self, uid: u32, gid: u32, access: u16) -> bool {
+        let mode = if uid == 0 {
+            return true;
+        } else if uid == self.uid {
+            self.mode >> 6
+        } else if gid == self.gid {
+            self.mode >> 3
+        } else {
+            self.mode
+        };
+        mode & access == access
+    }
+
+    /// Return true if the user may change the mode or owner, or remove
+    /// the name
+    pub fn owned_by(&self, uid: u32) -> bool {
+        uid == 0 || uid == self.uid
+    }
+
+    /// Change the owner like chown, with -1 leaving that part alone. Only
+    /// root may give it away, and the owner may only hand it to their own
+    /// group
+    pub fn chown(&mut self, caller_uid: u32, caller_gid: u32, uid: u32, gid: u32) -> Result<()> {
+        if !self.owned_by(caller_uid) {
+            return Err(Error::new(EPERM));
+        }
+        if caller_uid != 0 {
+            if uid != u32::MAX && uid != self.uid {
+                return Err(Error::new(EPERM));
+            }
+            if gid != u32::MAX && gid != self.gid && gid != caller_gid {
+                return Err(Error::new(EPERM));
+            }
+        }
+        if uid != u32::MAX {
+            self.uid = uid;
+        }
+        if gid != u32::MAX {
+            self.gid = gid;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    mem,
+    rc::Rc,
+    sync::atomic::{fence, AtomicU32, Ordering},
+};
+use syscall::{flag::*, error::*, Error, MapFlags, PAGE_SIZE};
+use redox_scheme::{SchemeBlockMut, V2};
+use ipcd::flag::{F_GETPIPE_SZ, F_SETPIPE_SZ};
+use ipcd::ringbuf::{Header, DATA_OFFSET, DEFAULT_CAPACITY};
+
+use crate::path;
+use crate::shm::MmapGuard;
+use crate::wait::{self, BlockScheme, Blocked};
+
+/// Length of the counts returned by waiting
+pub const COUNT_LEN: usize = mem::size_of::<usize>();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum End {
+    Producer,
+    Consumer,
+}
+
+pub struct Ring {
+    path: Rc<str>,
+    /// Header and data, allocated once someone maps or waits on the ring
+    buffer: Option<MmapGuard>,
+    capacity: usize,
+    producer: Option<usize>,
+    consumer: Option<usize>,
+    refs: usize,
+}
+impl Ring {
+    /// Allocate the shared memory if that didn't happen yet, and return
+    /// its header
+    pub fn setup(&mut self) -> Result<&Header> {
+        if self.buffer.is_none() {
+            let buffer = MmapGuard::alloc((DATA_OFFSET + self.capacity).div_ceil(PAGE_SIZE))?;
+            // Fresh memory is zeroed, so only the capacity is left to set
+            let header = unsafe { &*(buffer.as_ptr() as *const Header) };
+            header.capacity.store(self.capacity, Ordering::SeqCst);
+            self.buffer = Some(buffer);
+        }
+        Ok(self.header().unwrap())
+    }
+
+    pub fn header(&self) -> Option<&Header> {
+        self.buffer.as_ref().map(|buffer| unsafe { &*(buffer.as_ptr() as *const Header) })
+    }
+}
+
+/// Return how much an end can do right now: bytes to read for the
+/// consumer (0 once the producer is gone) and space to write for the
+/// producer. Fails with EPIPE once the consumer is gone
+fn ready(header: &Header, end: End) -> Result<Option<usize>> {
+    let capacity = header.capacity.load(Ordering::SeqCst);
+    let used = header.tail.load(Ordering::SeqCst).wrapping_sub(header.head.load(Ordering::SeqCst));
+    match end {
+        End::Consumer if used > 0 => Ok(Some(used)),
+        End::Consumer if header.producer_closed.load(Ordering::SeqCst) != 0 => Ok(Some(0)),
+        End::Consumer => Ok(None),
+        End::Producer if header.consumer_closed.load(Ordering::SeqCst) != 0 => Err(Error::new(EPIPE)),
+        End::Producer if used < capacity => Ok(Some(capacity - used)),
+        End::Producer => Ok(None)
+    }
+}
+
+/// The flag an end's waiting is announced in, and what requests of that
+/// end wait for
+fn waiting(header: &Header, end: End) -> (&AtomicU32, EventFlags) {
+    match end {
+        End::Consumer => (&header.consumer_waiting, EVENT_READ),
+        End::Producer => (&header.producer_waiting, EVENT_WRITE)
+    }
+}
+
+pub struct Handle {
+    flags: usize,
+    /// Also what requests blocked on the ring wait on
+    ring: usize,
+    end: End,
+    /// Set while someone wants events for this handle
+    subscribed: bool,
+}
+
+pub struct RingScheme {
+    names: HashMap<Rc<str>, usize>,
+    rings: HashMap<usize, Ring>,
+    handles: HashMap<usize, Handle>,
+    next_id: usize,
+    blocked: Blocked,
+    pub socket: redox_scheme::Socket,
+}
+impl RingScheme {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            names: HashMap::new(),
+            rings: HashMap::new(),
+            handles: HashMap::new(),
+            next_id: 0,
+            blocked: Blocked::default(),
+            socket: redox_scheme::Socket::<V2>::nonblock("ring")?,
+        })
+    }
+
+    /// Post an event for an end of a ring and wake up requests blocked on
+    /// it
+    fn notify(&mut self, ring_id: usize, end: End) -> Result<()> {
+        let ring = &self.rings[&ring_id];
+        let (id, events) = match end {
+            End::Consumer => (ring.consumer, EVENT_READ),
+            End::Producer => (ring.producer, EVENT_WRITE)
+        };
+        wait::notify(&self.socket, &mut self.blocked, id.as_slice(), ring_id, events)
+    }
+}
+
+impl BlockScheme for RingScheme {
+    fn socket(&self) -> &redox_scheme::Socket {
+        &self.socket
+    }
+    fn blocked(&mut self) -> &mut Blocked {
+        &mut self.blocked
+    }
+}
+
+impl SchemeBlockMut for RingScheme {
+    fn open(&mut self, path: &str, flags: usize, _uid: u32, _gid: u32) -> Result<Option<usize>> {
+        if path.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+        // A ring has exactly one end of each
+        let end = match flags & O_ACCMODE {
+            O_RDONLY => End::Consumer,
+            O_WRONLY => End::Producer,
+            _ => return Err(Error::new(EINVAL))
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let ring_id = match self.names.get(path) {
+            Some(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => return Err(Error::new(EEXIST)),
+            Some(&ring_id) => ring_id,
+            None if flags & O_CREAT == O_CREAT => {
+                let ring_id = self.next_id;
+                self.next_id += 1;
+
+                let path = Rc::from(path);
+                self.names.insert(Rc::clone(&path), ring_id);
+                self.rings.insert(ring_id, Ring {
+                    path,
+                    buffer: None,
+                    capacity: DEFAULT_CAPACITY,
+                    producer: None,
+                    consumer: None,
+                    refs: 0,
+                });
+                ring_id
+            },
+            None => return Err(Error::new(ENOENT))
+        };
+
+        let ring = self.rings.get_mut(&ring_id).expect("name pointing to nothing");
+        let slot = match end {
+            End::Consumer => &mut ring.consumer,
+            End::Producer => &mut ring.producer
+        };
+        if slot.is_some() {
+            return Err(Error::new(EBUSY));
+        }
+        *slot = Some(id);
+        ring.refs += 1;
+        if let Some(header) = ring.header() {
+            // Taking the place of an end that closed before
+            match end {
+                End::Consumer => header.consumer_closed.store(0, Ordering::SeqCst),
+                End::Producer => header.producer_closed.store(0, Ordering::SeqCst)
+            }
+        }
+
+        self.handles.insert(id, Handle {
+            flags,
+            ring: ring_id,
+            end,
+            subscribed: false,
+        });
+        Ok(Some(id))
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8], _offset: u64, flags: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let (ring_id, end, subscribed) = (handle.ring, handle.end, handle.subscribed);
+        let header = self.rings.get_mut(&ring_id).expect("handle pointing to nothing").setup()?;
+
+        if buf.len() < COUNT_LEN {
+            return Err(Error::new(EINVAL));
+        }
+
+        // Announce waiting before looking, so either this sees what the
+        // other end did, or the other end sees this and rings
+        let (flag, events) = waiting(header, end);
+        flag.store(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        match ready(header, end)? {
+            Some(count) => {
+                if !subscribed && self.blocked.waiting(ring_id, events) == 0 {
+                    flag.store(0, Ordering::SeqCst);
+                }
+                buf[..COUNT_LEN].copy_from_slice(&count.to_ne_bytes());
+                Ok(Some(COUNT_LEN))
+            },
+            None if (flags as usize) & O_NONBLOCK == O_NONBLOCK => Err(Error::new(EAGAIN)),
+            None => self.blocked.block(ring_id, events, None)
+        }
+    }
+    fn write(&mut self, id: usize, buf: &[u8], _offset: u64, _flags: u32) -> Result<Option<usize>> {
+        // The ring changed, so whatever the other end waits for might be
+        // there
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let (ring_id, end) = (handle.ring, handle.end);
+        match end {
+            End::Consumer => self.notify(ring_id, End::Producer)?,
+            End::Producer => self.notify(ring_id, End::Consumer)?
+        }
+        Ok(Some(buf.len()))
+    }
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        Ok(Some(path::fpath(b"ring:", &self.rings[&handle.ring].path, buf)))
+    }
+    fn fcntl(&mut self, id: usize, cmd: usize, arg: usize) -> Result<Option<usize>> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let ring = self.rings.get_mut(&handle.ring).expect("handle pointing to nothing");
+        match cmd {
+            F_GETFL => Ok(Some(handle.flags)),
+            F_SETFL => {
+                handle.flags = arg;
+                Ok(Some(0))
+            },
+            F_GETPIPE_SZ => {
+                // Whoever asks is about to map it, so the size is final
+                // from here on and both ends agree on it
+                ring.setup()?;
+                Ok(Some(ring.capacity))
+            },
+            F_SETPIPE_SZ => {
+                // The size can't change once an end read it back or mapped it
+                if ring.buffer.is_some() {
+                    return Err(Error::new(EBUSY));
+                }
+                if !arg.is_power_of_two() || arg < PAGE_SIZE {
+                    return Err(Error::new(EINVAL));
+                }
+                ring.capacity = arg;
+                Ok(Some(arg))
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+    fn fevent(&mut self, id: usize, flags: EventFlags) -> Result<Option<EventFlags>> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        handle.subscribed = !flags.is_empty();
+        let (subscribed, end) = (handle.subscribed, handle.end);
+        let header = self.rings.get_mut(&handle.ring).expect("handle pointing to nothing").setup()?;
+
+        // Events only arrive if the other end rings
+        let (flag, events) = waiting(header, end);
+        if subscribed {
+            flag.store(1, Ordering::SeqCst);
+            fence(Ordering::SeqCst);
+        }
+        match ready(header, end) {
+            Ok(None) => Ok(Some(EventFlags::empty())),
+            Ok(Some(_)) | Err(_) => Ok(Some(events))
+        }
+    }
+    fn close(&mut self, id: usize) -> Result<Option<usize>> {
+        let handle = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+        let ring = self.rings.get_mut(&handle.ring).expect("handle pointing to nothing");
+        match handle.end {
+            End::Consumer => ring.consumer = None,
+            End::Producer => ring.producer = None
+        }
+        if let Some(header) = ring.header() {
+            match handle.end {
+                End::Consumer => header.consumer_closed.store(1, Ordering::SeqCst),
+                End::Producer => header.producer_closed.store(1, Ordering::SeqCst)
+            }
+        }
+        ring.refs -= 1;
+        if ring.refs == 0 {
+            // Nobody uses this ring anymore, forget it
+            let ring = self.rings.remove(&handle.ring).unwrap();
+            self.names.remove(&ring.path);
+            return Ok(Some(0));
+        }
+
+        // The other end doesn't have to wait anymore
+        match handle.end {
+            End::Consumer => self.notify(handle.ring, End::Producer)?,
+            End::Producer => self.notify(handle.ring, End::Consumer)?
+        }
+        Ok(Some(0))
+    }
+    fn mmap_prep(&mut self, id: usize, offset: u64, size: usize, _: MapFlags) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let ring = self.rings.get_mut(&handle.ring).expect("handle pointing to nothing");
+        ring.setup()?;
+        let buffer = ring.buffer.as_ref().unwrap();
+        if offset as usize + size > buffer.len() {
+            return Err(Error::new(ERANGE));
+        }
+        Ok(Some(buffer.as_ptr() + offset as usize))
+    }
+}
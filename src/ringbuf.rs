@@ -0,0 +1,212 @@
+//! Single-producer, single-consumer byte rings in memory shared through
+//! `ring:<name>`. Data never passes through ipcd, which is only asked to
+//! wait when the ring is empty (or full), and told when that changes.
+
+use std::{
+    cmp,
+    fs::{File, OpenOptions},
+    io,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    slice,
+    sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering},
+};
+
+use crate::flag::{F_GETPIPE_SZ, F_SETPIPE_SZ};
+
+/// Where the data starts in the mapping, after the header
+pub const DATA_OFFSET: usize = syscall::PAGE_SIZE;
+
+/// Size of the data of rings set up without any, unless changed with
+/// `F_SETPIPE_SZ`
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+/// The start of the mapping, shared by both ends and ipcd
+#[repr(C)]
+pub struct Header {
+    /// Bytes the consumer took out so far, wrapping around
+    pub head: AtomicUsize,
+    /// Bytes the producer put in so far, wrapping around
+    pub tail: AtomicUsize,
+    /// Size of the data, a power of two
+    pub capacity: AtomicUsize,
+    /// Set by ipcd while the consumer waits for data, so the producer
+    /// knows to ring it
+    pub consumer_waiting: AtomicU32,
+    /// Set by ipcd while the producer waits for space
+    pub producer_waiting: AtomicU32,
+    /// Set by ipcd when an end closes its handle
+    pub producer_closed: AtomicU32,
+    pub consumer_closed: AtomicU32,
+}
+
+fn from_syscall_error(error: syscall::Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+
+/// One end's handle and mapping of a ring
+struct Mapping {
+    file: File,
+    base: usize,
+    capacity: usize,
+}
+impl Mapping {
+    fn open(name: &str, options: &mut OpenOptions, capacity: usize) -> io::Result<Self> {
+        // std only lets handles opened for writing create files, so ask
+        // for O_CREAT directly
+        let file = options.custom_flags(syscall::O_CREAT as i32).open(format!("ring:{}", name))?;
+        let fd = file.as_raw_fd() as usize;
+
+        // Whatever size was set last before either end read it back is
+        // the one both ends get, later attempts fail with EBUSY
+        match syscall::fcntl(fd, F_SETPIPE_SZ, capacity) {
+            Ok(_) | Err(syscall::Error { errno: syscall::EBUSY }) => (),
+            Err(error) => return Err(from_syscall_error(error))
+        }
+        let capacity = syscall::fcntl(fd, F_GETPIPE_SZ, 0).map_err(from_syscall_error)?;
+
+        let base = unsafe {
+            syscall::fmap(fd, &syscall::Map {
+                offset: 0,
+                address: 0,
+                size: DATA_OFFSET + capacity,
+                flags: syscall::PROT_READ | syscall::PROT_WRITE | syscall::MAP_SHARED,
+            }).map_err(from_syscall_error)?
+        };
+        Ok(Self { file, base, capacity })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.base as *const Header) }
+    }
+    fn data(&self) -> *mut u8 {
+        (self.base + DATA_OFFSET) as *mut u8
+    }
+
+    /// Ask ipcd to wait until there's something to do, and return how
+    /// many bytes there are to read or write. 0 means the producer is
+    /// gone
+    fn wait(&self) -> io::Result<usize> {
+        let mut count = [0; std::mem::size_of::<usize>()];
+        syscall::read(self.file.as_raw_fd() as usize, &mut count).map_err(from_syscall_error)?;
+        Ok(usize::from_ne_bytes(count))
+    }
+
+    /// Tell the other end the ring changed, if it's waiting for that
+    fn ring(&self, waiting: &AtomicU32) -> io::Result<()> {
+        // Pairs with ipcd setting the flag before looking at the ring
+        fence(Ordering::SeqCst);
+        if waiting.load(Ordering::SeqCst) != 0 {
+            syscall::write(self.file.as_raw_fd() as usize, &[0]).map_err(from_syscall_error)?;
+        }
+        Ok(())
+    }
+}
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let _ = unsafe { syscall::funmap(self.base, DATA_OFFSET + self.capacity) };
+    }
+}
+
+/// The writing end of a ring
+pub struct Producer(Mapping);
+impl Producer {
+    /// Open the writing end of a ring, creating it if needed. `capacity`
+    /// has to be a power of two of at least a page, and is ignored if the
+    /// other end set up the ring already
+    pub fn open(name: &str, capacity: usize) -> io::Result<Self> {
+        Mapping::open(name, OpenOptions::new().write(true), capacity).map(Self)
+    }
+
+    /// Return the free space following what was written so far, waiting
+    /// until there is some. Fails with EPIPE once the consumer is gone
+    pub fn grant(&mut self) -> io::Result<&mut [u8]> {
+        let header = self.0.header();
+        let capacity = self.0.capacity;
+        loop {
+            let tail = header.tail.load(Ordering::Relaxed);
+            let used = tail.wrapping_sub(header.head.load(Ordering::Acquire));
+            if used < capacity {
+                let index = tail & (capacity - 1);
+                let len = cmp::min(capacity - used, capacity - index);
+                return Ok(unsafe { slice::from_raw_parts_mut(self.0.data().add(index), len) });
+            }
+            self.0.wait()?;
+        }
+    }
+
+    /// Hand `len` bytes written to what `grant` returned to the consumer
+    pub fn commit(&mut self, len: usize) -> io::Result<()> {
+        let header = self.0.header();
+        let tail = header.tail.load(Ordering::Relaxed);
+        assert!(tail.wrapping_sub(header.head.load(Ordering::Acquire)) + len <= self.0.capacity, "committing more than was granted");
+        header.tail.store(tail.wrapping_add(len), Ordering::Release);
+        self.0.ring(&header.consumer_waiting)
+    }
+}
+impl io::Write for Producer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let space = self.grant()?;
+        let len = cmp::min(space.len(), buf.len());
+        space[..len].copy_from_slice(&buf[..len]);
+        self.commit(len)?;
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The reading end of a ring
+pub struct Consumer(Mapping);
+impl Consumer {
+    /// Open the reading end of a ring, creating it if needed. `capacity`
+    /// has to be a power of two of at least a page, and is ignored if the
+    /// other end set up the ring already
+    pub fn open(name: &str, capacity: usize) -> io::Result<Self> {
+        Mapping::open(name, OpenOptions::new().read(true), capacity).map(Self)
+    }
+
+    /// Return the data that's next in line, waiting until there is some.
+    /// Nothing is returned once the producer is gone and everything was
+    /// read
+    pub fn peek(&mut self) -> io::Result<&[u8]> {
+        let header = self.0.header();
+        let capacity = self.0.capacity;
+        loop {
+            let head = header.head.load(Ordering::Relaxed);
+            let available = header.tail.load(Ordering::Acquire).wrapping_sub(head);
+            if available > 0 {
+                let index = head & (capacity - 1);
+                let len = cmp::min(available, capacity - index);
+                return Ok(unsafe { slice::from_raw_parts(self.0.data().add(index), len) });
+            }
+            if self.0.wait()? == 0 {
+                return Ok(&[]);
+            }
+        }
+    }
+
+    /// Give `len` bytes of what `peek` returned back to the producer
+    pub fn consume(&mut self, len: usize) -> io::Result<()> {
+        let header = self.0.header();
+        let head = header.head.load(Ordering::Relaxed);
+        assert!(len <= header.tail.load(Ordering::Acquire).wrapping_sub(head), "consuming more than is available");
+        header.head.store(head.wrapping_add(len), Ordering::Release);
+        self.0.ring(&header.producer_waiting)
+    }
+}
+impl io::Read for Consumer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let data = self.peek()?;
+        let len = cmp::min(data.len(), buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.consume(len)?;
+        Ok(len)
+    }
+}
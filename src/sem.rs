@@ -0,0 +1,225 @@
+use std::{
+    collections::HashMap,
+    mem,
+    rc::Rc,
+};
+use syscall::{flag::*, error::*, Error};
+use redox_scheme::{SchemeBlockMut, V2};
+use ipcd::flag::*;
+
+use crate::path;
+use crate::perm::{Perm, READ, WRITE};
+use crate::wait::{self, BlockScheme, Blocked, Timeouts};
+
+/// Largest value a semaphore may have, same as relibc
+pub const SEM_VALUE_MAX: usize = i32::MAX as usize;
+
+/// Length of the counts read and written
+pub const COUNT_LEN: usize = mem::size_of::<usize>();
+
+pub struct Semaphore {
+    /// Name to open it by, until it's unlinked
+    path: Rc<str>,
+    linked: bool,
+    value: usize,
+    /// Owner and permissions, checked when opening
+    perm: Perm,
+    handles: Vec<usize>,
+}
+pub struct Handle {
+    flags: usize,
+    /// Also what requests blocked on the semaphore wait on
+    sem: usize,
+    timeouts: Timeouts,
+}
+
+pub struct SemScheme {
+    names: HashMap<Rc<str>, usize>,
+    sems: HashMap<usize, Semaphore>,
+    handles: HashMap<usize, Handle>,
+    next_id: usize,
+    blocked: Blocked,
+    pub socket: redox_scheme::Socket,
+}
+impl SemScheme {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            names: HashMap::new(),
+            sems: HashMap::new(),
+            handles: HashMap::new(),
+            next_id: 0,
+            blocked: Blocked::default(),
+            socket: redox_scheme::Socket::<V2>::nonblock("sem")?,
+        })
+    }
+
+    /// Post an event for every handle of a semaphore and wake up requests
+    /// blocked on it
+    fn notify(&mut self, sem_id: usize, events: EventFlags) -> Result<()> {
+        wait::notify(&self.socket, &mut self.blocked, &self.sems[&sem_id].handles, sem_id, events)
+    }
+}
+
+impl BlockScheme for SemScheme {
+    fn socket(&self) -> &redox_scheme::Socket {
+        &self.socket
+    }
+    fn blocked(&mut self) -> &mut Blocked {
+        &mut self.blocked
+    }
+}
+
+impl SchemeBlockMut for SemScheme {
+    fn open(&mut self, path: &str, flags: usize, uid: u32, gid: u32) -> Result<Option<usize>> {
+        // Like the value passed to sem_open, this only applies to a
+        // semaphore that is created by this open
+        let (path, value) = match path.rsplit_once('?') {
+            Some((path, value)) if flags & O_CREAT == O_CREAT => {
+                match value.parse() {
+                    Ok(value) if value <= SEM_VALUE_MAX => (path, Some(value)),
+                    _ => return Err(Error::new(EINVAL))
+                }
+            },
+            _ => (path, None)
+        };
+        if path.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let sem_id = match self.names.get(path) {
+            Some(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => return Err(Error::new(EEXIST)),
+            Some(&sem_id) => {
+                let sem = self.sems.get_mut(&sem_id).expect("name pointing to nothing");
+                // Like sem_open, this needs read and write permission
+                if !sem.perm.allows(uid, gid, READ | WRITE) {
+                    return Err(Error::new(EACCES));
+                }
+                sem.handles.push(id);
+                sem_id
+            },
+            None if flags & O_CREAT == O_CREAT => {
+                let sem_id = self.next_id;
+                self.next_id += 1;
+
+                let path = Rc::from(path);
+                self.names.insert(Rc::clone(&path), sem_id);
+                self.sems.insert(sem_id, Semaphore {
+                    path,
+                    linked: true,
+                    value: value.unwrap_or(0),
+                    perm: Perm::new(uid, gid, flags),
+                    handles: vec![id],
+                });
+                sem_id
+            },
+            None => return Err(Error::new(ENOENT))
+        };
+
+        self.handles.insert(id, Handle {
+            flags,
+            sem: sem_id,
+            timeouts: Timeouts::default(),
+        });
+        Ok(Some(id))
+    }
+    fn unlink(&mut self, path: &str, uid: u32, _gid: u32) -> Result<Option<usize>> {
+        let &sem_id = self.names.get(path).ok_or(Error::new(ENOENT))?;
+        let sem = self.sems.get_mut(&sem_id).expect("name pointing to nothing");
+        if !sem.perm.owned_by(uid) {
+            return Err(Error::new(EACCES));
+        }
+        self.names.remove(path);
+        sem.linked = false;
+        if sem.handles.is_empty() {
+            self.sems.remove(&sem_id);
+        }
+        Ok(Some(0))
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8], _offset: u64, _flags: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let sem_id = handle.sem;
+        let sem = self.sems.get_mut(&sem_id).expect("handle pointing to nothing");
+
+        // Posting adds the count that's written
+        if buf.len() != COUNT_LEN {
+            return Err(Error::new(EINVAL));
+        }
+        let mut count = [0; COUNT_LEN];
+        count.copy_from_slice(buf);
+        let count = usize::from_ne_bytes(count);
+        match sem.value.checked_add(count) {
+            Some(value) if value <= SEM_VALUE_MAX => sem.value = value,
+            _ => return Err(Error::new(EOVERFLOW))
+        }
+        if sem.value > 0 {
+            self.notify(sem_id, EVENT_READ)?;
+        }
+
+        Ok(Some(COUNT_LEN))
+    }
+    fn read(&mut self, id: usize, buf: &mut [u8], _offset: u64, flags: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let sem_id = handle.sem;
+        let timeout = handle.timeouts.read;
+        let sem = self.sems.get_mut(&sem_id).expect("handle pointing to nothing");
+
+        if buf.len() < COUNT_LEN {
+            return Err(Error::new(EINVAL));
+        }
+        if sem.value == 0 || self.blocked.queued(sem_id, EVENT_READ) {
+            return if (flags as usize) & O_NONBLOCK == O_NONBLOCK {
+                Err(Error::new(EAGAIN))
+            } else {
+                self.blocked.block(sem_id, EVENT_READ, timeout)
+            };
+        }
+
+        // Waiting takes one off, and reads back how much that was
+        sem.value -= 1;
+        buf[..COUNT_LEN].copy_from_slice(&1usize.to_ne_bytes());
+        Ok(Some(COUNT_LEN))
+    }
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        Ok(Some(path::fpath(b"sem:", &self.sems[&handle.sem].path, buf)))
+    }
+    fn fcntl(&mut self, id: usize, cmd: usize, arg: usize) -> Result<Option<usize>> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let sem = &self.sems[&handle.sem];
+        match cmd {
+            F_GETFL => Ok(Some(handle.flags)),
+            F_SETFL => {
+                handle.flags = arg;
+                Ok(Some(0))
+            },
+            F_GETVALUE => Ok(Some(sem.value)),
+            F_GETRCVTIMEO | F_SETRCVTIMEO => handle.timeouts.fcntl(cmd, arg).map(Some),
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+    fn fevent(&mut self, id: usize, _flags: EventFlags) -> Result<Option<EventFlags>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let sem = &self.sems[&handle.sem];
+        let mut events = EventFlags::empty();
+        if sem.value > 0 {
+            events |= EVENT_READ;
+        }
+        if sem.value < SEM_VALUE_MAX {
+            events |= EVENT_WRITE;
+        }
+        Ok(Some(events))
+    }
+    fn close(&mut self, id: usize) -> Result<Option<usize>> {
+        let handle = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+        let sem = self.sems.get_mut(&handle.sem).expect("handle pointing to nothing");
+        sem.handles.retain(|&handle_id| handle_id != id);
+        if sem.handles.is_empty() && !sem.linked {
+            // Unlinked and nobody has it open anymore
+            self.sems.remove(&handle.sem);
+        }
+        Ok(Some(0))
+    }
+}
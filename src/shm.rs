@@ -1,20 +1,82 @@
 use std::{
     cmp,
     collections::{HashMap, hash_map::Entry},
+    mem,
     rc::Rc,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
 };
-use syscall::{error::*, Error, Map, Result, MapFlags, PAGE_SIZE, MAP_PRIVATE};
-use redox_scheme::{SchemeMut, V2};
+use syscall::{error::*, flag::{EventFlags, EVENT_READ}, Error, Map, Result, MapFlags, PAGE_SIZE, MAP_PRIVATE};
+use redox_scheme::{SchemeBlockMut, V2};
+
+use crate::path;
+use crate::wait::{BlockScheme, Blocked};
+
+// Operations written to a shm handle, same numbers as Linux
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+
+/// What's written to a shm handle to wait on a 32-bit word in the
+/// segment, or to wake those waiting on it
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FutexOp {
+    pub op: usize,
+    /// Where the word is in the segment, aligned to 4 bytes
+    pub offset: usize,
+    /// For `FUTEX_WAIT`, what the word has to be to start waiting. For
+    /// `FUTEX_WAKE`, how many waiters to wake at most
+    pub value: usize,
+    /// For `FUTEX_WAIT`, how many nanoseconds to wait at most, or 0 to
+    /// wait until woken
+    pub timeout: usize,
+}
+impl FutexOp {
+    /// Parse the native-endian `usize` fields, in order
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        const WORD: usize = mem::size_of::<usize>();
+        if buf.len() != 4 * WORD {
+            return Err(Error::new(EINVAL));
+        }
+        let field = |i: usize| {
+            let mut word = [0; WORD];
+            word.copy_from_slice(&buf[i * WORD..][..WORD]);
+            usize::from_ne_bytes(word)
+        };
+        Ok(Self {
+            op: field(0),
+            offset: field(1),
+            value: field(2),
+            timeout: field(3),
+        })
+    }
+}
+
+/// Requests waiting on one word of a segment
+pub struct Futex {
+    /// What they're blocked on
+    id: usize,
+    /// How many of them are being tried again after a wake, and still
+    /// waiting no matter what the word is now
+    woken: usize,
+    /// How many of those may stop waiting
+    wakes: usize,
+}
 
 #[derive(Default)]
 pub struct ShmHandle {
     buffer: Option<MmapGuard>,
+    /// Words waited on, by offset
+    futexes: HashMap<usize, Futex>,
     refs: usize
 }
 pub struct ShmScheme {
     maps: HashMap<Rc<str>, ShmHandle>,
     handles: HashMap<usize, Rc<str>>,
+    /// Segment and offset of every word waited on, by what its waiters
+    /// are blocked on
+    futexes: HashMap<usize, (Rc<str>, usize)>,
     next_id: usize,
+    blocked: Blocked,
     pub socket: redox_scheme::Socket,
 }
 impl ShmScheme {
@@ -22,40 +84,139 @@ impl ShmScheme {
         Ok(Self {
             maps: HashMap::new(),
             handles: HashMap::new(),
+            futexes: HashMap::new(),
             next_id: 0,
+            blocked: Blocked::default(),
             socket: redox_scheme::Socket::<V2>::nonblock("shm")?,
         })
     }
+
+    /// Wait until woken if the word at `offset` still has the expected
+    /// value, failing with EAGAIN otherwise
+    fn futex_wait(&mut self, id: usize, op: FutexOp) -> Result<Option<usize>> {
+        // The word is only 32 bits, so it could never equal anything larger
+        if op.value > u32::MAX as usize {
+            return Err(Error::new(EINVAL));
+        }
+        let path = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let entry = self.maps.get_mut(path).expect("handle pointing to nothing");
+        // The word has to be in the part that's mapped
+        let buffer = entry.buffer.as_ref().ok_or(Error::new(EINVAL))?;
+        if !op.offset.is_multiple_of(mem::size_of::<u32>()) || op.offset.checked_add(mem::size_of::<u32>()).is_none_or(|end| end > buffer.len()) {
+            return Err(Error::new(EINVAL));
+        }
+        let word = unsafe { &*((buffer.as_ptr() + op.offset) as *const AtomicU32) };
+
+        let next_id = &mut self.next_id;
+        let futexes = &mut self.futexes;
+        let futex = entry.futexes.entry(op.offset).or_insert_with(|| {
+            let id = *next_id;
+            *next_id += 1;
+            futexes.insert(id, (Rc::clone(path), op.offset));
+            Futex { id, woken: 0, wakes: 0 }
+        });
+        let timeout = Some(Duration::from_nanos(op.timeout as u64)).filter(|timeout| !timeout.is_zero());
+
+        let result = if futex.woken > 0 {
+            // Only a wake ends a wait, the word changing doesn't
+            futex.woken -= 1;
+            if futex.wakes > 0 {
+                futex.wakes -= 1;
+                Ok(Some(0))
+            } else {
+                self.blocked.block(futex.id, EVENT_READ, timeout)
+            }
+        } else if word.load(Ordering::SeqCst) != op.value as u32 {
+            Err(Error::new(EAGAIN))
+        } else {
+            self.blocked.block(futex.id, EVENT_READ, timeout)
+        };
+
+        let futex_id = futex.id;
+        self.forget_futex(futex_id);
+        result
+    }
+
+    /// Forget a word once nobody waits on it anymore
+    fn forget_futex(&mut self, futex_id: usize) {
+        if self.blocked.waiting(futex_id, EVENT_READ) > 0 {
+            return;
+        }
+        let Some((path, offset)) = self.futexes.get(&futex_id) else {
+            return;
+        };
+        // The segment is gone if every handle to it was closed
+        if let Some(entry) = self.maps.get_mut(path) {
+            if entry.futexes.get(offset).is_some_and(|futex| futex.woken > 0) {
+                return;
+            }
+            entry.futexes.remove(offset);
+        }
+        self.futexes.remove(&futex_id);
+    }
+
+    /// Wake up to `op.value` requests waiting on the word at `offset`, the
+    /// oldest first, and return how many that was
+    fn futex_wake(&mut self, id: usize, op: FutexOp) -> Result<Option<usize>> {
+        let path = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let entry = self.maps.get_mut(path).expect("handle pointing to nothing");
+        let futex = match entry.futexes.get_mut(&op.offset) {
+            Some(futex) => futex,
+            None => return Ok(Some(0))
+        };
+
+        let waiting = self.blocked.waiting(futex.id, EVENT_READ);
+        let wakes = cmp::min(op.value, waiting);
+        if wakes > 0 {
+            // Every waiter is tried again in order, and the first ones
+            // stop waiting
+            futex.woken = waiting;
+            futex.wakes = wakes;
+            self.blocked.wake(futex.id, EVENT_READ);
+        }
+        Ok(Some(wakes))
+    }
 }
 
-impl SchemeMut for ShmScheme {
-    fn open(&mut self, path: &str, _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+impl BlockScheme for ShmScheme {
+    fn socket(&self) -> &redox_scheme::Socket {
+        &self.socket
+    }
+    fn blocked(&mut self) -> &mut Blocked {
+        &mut self.blocked
+    }
+    fn unblocked(&mut self, id: usize, events: EventFlags) {
+        // Trying the others again would check the word and let them go
+        // without being woken
+        self.blocked.leave(id, events);
+        self.forget_futex(id);
+    }
+}
+
+impl SchemeBlockMut for ShmScheme {
+    fn open(&mut self, path: &str, _flags: usize, _uid: u32, _gid: u32) -> Result<Option<usize>> {
         let path = Rc::from(path);
-        let entry = self.maps.entry(Rc::clone(&path)).or_insert(ShmHandle::default());
+        let entry = self.maps.entry(Rc::clone(&path)).or_default();
         entry.refs += 1;
         self.handles.insert(self.next_id, path);
 
         let id = self.next_id;
         self.next_id += 1;
-        Ok(id)
-    }
-    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
-        // Write scheme name
-        const PREFIX: &[u8] = b"shm:";
-        let len = cmp::min(PREFIX.len(), buf.len());
-        buf[..len].copy_from_slice(&PREFIX[..len]);
-        if len < PREFIX.len() {
-            return Ok(len);
+        Ok(Some(id))
+    }
+    fn write(&mut self, id: usize, buf: &[u8], _offset: u64, _flags: u32) -> Result<Option<usize>> {
+        let op = FutexOp::from_bytes(buf)?;
+        match op.op {
+            FUTEX_WAIT => self.futex_wait(id, op),
+            FUTEX_WAKE => self.futex_wake(id, op),
+            _ => Err(Error::new(EINVAL))
         }
-
-        // Write path
+    }
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
         let path = self.handles.get(&id).ok_or(Error::new(EBADF))?;
-        let len = cmp::min(path.len(), buf.len() - PREFIX.len());
-        buf[PREFIX.len()..][..len].copy_from_slice(&path.as_bytes()[..len]);
-
-        Ok(PREFIX.len() + len)
+        Ok(Some(path::fpath(b"shm:", path, buf)))
     }
-    fn close(&mut self, id: usize) -> Result<usize> {
+    fn close(&mut self, id: usize) -> Result<Option<usize>> {
         let path = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
         let mut entry = match self.maps.entry(path) {
             Entry::Occupied(entry) => entry,
@@ -66,9 +227,9 @@ impl SchemeMut for ShmScheme {
             // There is no other reference to this entry, drop
             entry.remove_entry();
         }
-        Ok(0)
+        Ok(Some(0))
     }
-    fn mmap_prep(&mut self, id: usize, offset: u64, size: usize, _: MapFlags) -> Result<usize> {
+    fn mmap_prep(&mut self, id: usize, offset: u64, size: usize, _: MapFlags) -> Result<Option<usize>> {
         let path = self.handles.get(&id).ok_or(Error::new(EBADF))?;
         let total_size = offset as usize + size;
         match self.maps.get_mut(path).expect("handle pointing to nothing").buffer {
@@ -76,11 +237,11 @@ impl SchemeMut for ShmScheme {
                 if total_size > buf.len() {
                     return Err(Error::new(ERANGE));
                 }
-                Ok(buf.as_ptr() + offset as usize)
+                Ok(Some(buf.as_ptr() + offset as usize))
             },
             ref mut buf @ None => {
                 *buf = Some(MmapGuard::alloc(size.div_ceil(PAGE_SIZE))?);
-                Ok(buf.as_mut().unwrap().as_ptr() + offset as usize)
+                Ok(Some(buf.as_mut().unwrap().as_ptr() + offset as usize))
             }
         }
     }
@@ -0,0 +1,52 @@
+use std::time::Duration;
+use syscall::{data::TimeSpec, flag::{CLOCK_MONOTONIC, O_CLOEXEC, O_RDWR}, Result};
+
+/// Alarms on the monotonic clock, which show up as readable events on
+/// `fd`. Has to be opened before ipcd drops its namespace.
+pub struct Timer {
+    pub fd: usize,
+    /// Earliest alarm that didn't go off yet
+    armed: Option<Duration>,
+}
+impl Timer {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            fd: syscall::open(format!("time:{}", CLOCK_MONOTONIC), O_RDWR | O_CLOEXEC)?,
+            armed: None,
+        })
+    }
+
+    /// Return the current time of the monotonic clock
+    pub fn now(&self) -> Result<Duration> {
+        let mut time = TimeSpec::default();
+        syscall::read(self.fd, &mut time)?;
+        Ok(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+    }
+
+    /// Make sure an event arrives at `deadline` or earlier. Extra events
+    /// can arrive as well
+    pub fn arm(&mut self, deadline: Duration) -> Result<()> {
+        if self.armed.is_some_and(|armed| armed <= deadline) {
+            return Ok(());
+        }
+        let time = TimeSpec {
+            tv_sec: deadline.as_secs() as i64,
+            tv_nsec: deadline.subsec_nanos() as i32,
+        };
+        syscall::write(self.fd, &time)?;
+        self.armed = Some(deadline);
+        Ok(())
+    }
+
+    /// Forget the alarm if it went off by `now`
+    pub fn fired(&mut self, now: Duration) {
+        if self.armed.is_some_and(|armed| armed <= now) {
+            self.armed = None;
+        }
+    }
+}
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let _ = syscall::close(self.fd);
+    }
+}
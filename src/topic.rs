@@ -0,0 +1,349 @@
+use std::{
+    cmp,
+    collections::{HashMap, VecDeque},
+    mem,
+    rc::Rc,
+};
+use syscall::{flag::*, error::*, Error};
+use redox_scheme::{SchemeBlockMut, V2};
+use ipcd::flag::*;
+
+use crate::buffer::message_cost;
+use crate::path;
+use crate::wait::{BlockScheme, Blocked};
+
+/// Number of bytes a subscriber may have queued before its policy decides
+/// what happens to new messages, unless changed with `F_SETPIPE_SZ`
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Publishers wait until there is space
+    Block,
+    /// The oldest messages make room for the new one
+    DropOldest,
+    /// The subscriber loses everything and is cut off
+    Disconnect,
+}
+impl Policy {
+    pub fn from_raw(policy: usize) -> Result<Self> {
+        match policy {
+            POLICY_BLOCK => Ok(Policy::Block),
+            POLICY_DROP_OLDEST => Ok(Policy::DropOldest),
+            POLICY_DISCONNECT => Ok(Policy::Disconnect),
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+    pub fn to_raw(self) -> usize {
+        match self {
+            Policy::Block => POLICY_BLOCK,
+            Policy::DropOldest => POLICY_DROP_OLDEST,
+            Policy::Disconnect => POLICY_DISCONNECT
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Subscribed,
+    /// Cut off for falling behind, the next read fails with ECONNRESET
+    Reset,
+    /// Cut off and told so, reads return EOF
+    Disconnected,
+}
+
+#[derive(Debug)]
+pub struct Subscriber {
+    /// Messages not read yet, shared with the other subscribers
+    queue: VecDeque<Rc<[u8]>>,
+    /// How much of the capacity the messages in `queue` take up, every
+    /// message counting as at least one byte
+    queued: usize,
+    capacity: usize,
+    policy: Policy,
+    /// How many messages never made it into `queue`, or were pushed out
+    dropped: usize,
+    status: Status,
+    /// Publishers that found this full, to be told when there is space
+    writers: Vec<usize>,
+}
+impl Default for Subscriber {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            queued: 0,
+            capacity: DEFAULT_CAPACITY,
+            policy: Policy::Block,
+            dropped: 0,
+            status: Status::Subscribed,
+            writers: Vec::new(),
+        }
+    }
+}
+impl Subscriber {
+    /// Return true if publishers have to wait before sending a message of
+    /// `len` bytes
+    pub fn holds_back(&self, len: usize) -> bool {
+        self.policy == Policy::Block && self.status == Status::Subscribed && self.queued + message_cost(len) > self.capacity
+    }
+
+    /// Queue a message, or do whatever the policy says if it doesn't fit
+    pub fn deliver(&mut self, message: &Rc<[u8]>) {
+        if self.status != Status::Subscribed {
+            return;
+        }
+        let cost = message_cost(message.len());
+        if self.queued + cost > self.capacity {
+            match self.policy {
+                // Publishers made sure this fits
+                Policy::Block => unreachable!("delivering to a full subscriber"),
+                Policy::DropOldest => {
+                    // Never fits, so it doesn't push anything out
+                    if cost > self.capacity {
+                        self.dropped += 1;
+                        return;
+                    }
+                    while self.queued + cost > self.capacity && let Some(oldest) = self.queue.pop_front() {
+                        self.queued -= message_cost(oldest.len());
+                        self.dropped += 1;
+                    }
+                },
+                Policy::Disconnect => {
+                    self.dropped += self.queue.len() + 1;
+                    self.queue.clear();
+                    self.queued = 0;
+                    self.status = Status::Reset;
+                    return;
+                }
+            }
+        }
+        self.queue.push_back(Rc::clone(message));
+        self.queued += cost;
+    }
+}
+
+pub struct Handle {
+    flags: usize,
+    topic: Rc<str>,
+    /// Set if opened for reading
+    subscriber: Option<Subscriber>,
+    /// Set if opened for writing
+    publisher: bool,
+}
+impl Handle {
+    /// Error if this wasn't opened for reading
+    pub fn require_subscriber(&mut self) -> Result<&mut Subscriber> {
+        self.subscriber.as_mut().ok_or(Error::new(EBADF))
+    }
+}
+
+#[derive(Default)]
+pub struct Topic {
+    subscribers: Vec<usize>,
+    refs: usize,
+}
+
+pub struct TopicScheme {
+    topics: HashMap<Rc<str>, Topic>,
+    handles: HashMap<usize, Handle>,
+    next_id: usize,
+    blocked: Blocked,
+    pub socket: redox_scheme::Socket,
+}
+impl TopicScheme {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            topics: HashMap::new(),
+            handles: HashMap::new(),
+            next_id: 0,
+            blocked: Blocked::default(),
+            socket: redox_scheme::Socket::<V2>::nonblock("topic")?,
+        })
+    }
+
+    /// Post an event for a handle and wake up requests blocked on it
+    fn notify(&mut self, id: usize, events: EventFlags) -> Result<()> {
+        self.socket.post_fevent(id, events.bits())?;
+        self.blocked.wake(id, events);
+        Ok(())
+    }
+
+    /// Tell publishers waiting for space in a subscriber to try again
+    fn release_writers(&mut self, id: usize) -> Result<()> {
+        let writers = match self.handles.get_mut(&id).and_then(|handle| handle.subscriber.as_mut()) {
+            Some(subscriber) => mem::take(&mut subscriber.writers),
+            None => return Ok(())
+        };
+        for writer in writers {
+            self.notify(writer, EVENT_WRITE)?;
+        }
+        self.blocked.wake(id, EVENT_WRITE);
+        Ok(())
+    }
+}
+
+impl BlockScheme for TopicScheme {
+    fn socket(&self) -> &redox_scheme::Socket {
+        &self.socket
+    }
+    fn blocked(&mut self) -> &mut Blocked {
+        &mut self.blocked
+    }
+}
+
+impl SchemeBlockMut for TopicScheme {
+    fn open(&mut self, path: &str, flags: usize, _uid: u32, _gid: u32) -> Result<Option<usize>> {
+        if path.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+        let (read, write) = match flags & O_ACCMODE {
+            O_RDONLY => (true, false),
+            O_WRONLY => (false, true),
+            _ => (true, true)
+        };
+
+        let id = self.next_id;
+        let path = Rc::from(path);
+        let topic = self.topics.entry(Rc::clone(&path)).or_default();
+        topic.refs += 1;
+        if read {
+            topic.subscribers.push(id);
+        }
+
+        self.handles.insert(id, Handle {
+            flags,
+            topic: path,
+            subscriber: if read { Some(Subscriber::default()) } else { None },
+            publisher: write,
+        });
+        self.next_id += 1;
+        Ok(Some(id))
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8], _offset: u64, flags: u32) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        if !handle.publisher {
+            return Err(Error::new(EBADF));
+        }
+        let topic = &self.topics[&handle.topic];
+
+        // Messages go out to everyone or no one, so wait for the first
+        // subscriber that can't take it yet, or that others already wait
+        // for
+        for &sub_id in &topic.subscribers {
+            let subscriber = self.handles[&sub_id].subscriber.as_ref().expect("subscriber that doesn't read");
+            if subscriber.policy == Policy::Block && message_cost(buf.len()) > subscriber.capacity {
+                return Err(Error::new(EMSGSIZE));
+            }
+            if subscriber.holds_back(buf.len()) || self.blocked.queued(sub_id, EVENT_WRITE) {
+                let subscriber = self.handles.get_mut(&sub_id).unwrap().subscriber.as_mut().unwrap();
+                if !subscriber.writers.contains(&id) {
+                    subscriber.writers.push(id);
+                }
+                return if (flags as usize) & O_NONBLOCK == O_NONBLOCK {
+                    Err(Error::new(EAGAIN))
+                } else {
+                    self.blocked.block(sub_id, EVENT_WRITE, None)
+                };
+            }
+        }
+
+        let message = Rc::from(buf);
+        for sub_id in topic.subscribers.clone() {
+            let subscriber = self.handles.get_mut(&sub_id).unwrap().subscriber.as_mut().unwrap();
+            subscriber.deliver(&message);
+            self.notify(sub_id, EVENT_READ)?;
+        }
+        Ok(Some(buf.len()))
+    }
+    fn read(&mut self, id: usize, buf: &mut [u8], _offset: u64, flags: u32) -> Result<Option<usize>> {
+        if self.blocked.queued(id, EVENT_READ) {
+            return if (flags as usize) & O_NONBLOCK == O_NONBLOCK {
+                Err(Error::new(EAGAIN))
+            } else {
+                self.blocked.block(id, EVENT_READ, None)
+            };
+        }
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let subscriber = handle.require_subscriber()?;
+
+        if let Some(message) = subscriber.queue.pop_front() {
+            subscriber.queued -= message_cost(message.len());
+            // Whatever doesn't fit is thrown away
+            let len = cmp::min(buf.len(), message.len());
+            buf[..len].copy_from_slice(&message[..len]);
+            self.release_writers(id)?;
+            Ok(Some(len))
+        } else if subscriber.status == Status::Reset {
+            // Report falling behind once, then behave like the end
+            subscriber.status = Status::Disconnected;
+            Err(Error::new(ECONNRESET))
+        } else if subscriber.status == Status::Disconnected {
+            Ok(Some(0))
+        } else if (flags as usize) & O_NONBLOCK == O_NONBLOCK {
+            Err(Error::new(EAGAIN))
+        } else {
+            self.blocked.block(id, EVENT_READ, None)
+        }
+    }
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        Ok(Some(path::fpath(b"topic:", &handle.topic, buf)))
+    }
+    fn fcntl(&mut self, id: usize, cmd: usize, arg: usize) -> Result<Option<usize>> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        match cmd {
+            F_GETFL => Ok(Some(handle.flags)),
+            F_SETFL => {
+                handle.flags = arg;
+                Ok(Some(0))
+            },
+            F_GETPIPE_SZ => Ok(Some(handle.require_subscriber()?.capacity)),
+            F_SETPIPE_SZ => {
+                if arg == 0 {
+                    return Err(Error::new(EINVAL));
+                }
+                handle.require_subscriber()?.capacity = arg;
+                self.release_writers(id)?;
+                Ok(Some(arg))
+            },
+            F_GETPOLICY => Ok(Some(handle.require_subscriber()?.policy.to_raw())),
+            F_SETPOLICY => {
+                handle.require_subscriber()?.policy = Policy::from_raw(arg)?;
+                self.release_writers(id)?;
+                Ok(Some(0))
+            },
+            F_GETDROPPED => Ok(Some(handle.require_subscriber()?.dropped)),
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+    fn fevent(&mut self, id: usize, _flags: EventFlags) -> Result<Option<EventFlags>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let mut events = EventFlags::empty();
+        if let Some(ref subscriber) = handle.subscriber
+            && (!subscriber.queue.is_empty() || subscriber.status != Status::Subscribed) {
+            events |= EVENT_READ;
+        }
+        if handle.publisher && self.topics[&handle.topic].subscribers.iter().all(|sub_id| {
+            self.handles[sub_id].subscriber.as_ref().is_none_or(|subscriber| !subscriber.holds_back(1))
+        }) {
+            events |= EVENT_WRITE;
+        }
+        Ok(Some(events))
+    }
+    fn close(&mut self, id: usize) -> Result<Option<usize>> {
+        // Publishers waiting for this one don't have to anymore
+        self.release_writers(id)?;
+        self.blocked.wake(id, EVENT_READ | EVENT_WRITE);
+
+        let handle = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+        let topic = self.topics.get_mut(&handle.topic).expect("handle pointing to nothing");
+        topic.subscribers.retain(|&sub_id| sub_id != id);
+        topic.refs -= 1;
+        if topic.refs == 0 {
+            // Nobody uses this topic anymore, forget it
+            self.topics.remove(&handle.topic);
+        }
+        Ok(Some(0))
+    }
+}
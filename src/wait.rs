@@ -0,0 +1,489 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+    time::Duration,
+};
+use redox_scheme::{CallRequest, Request, RequestKind, Response, SchemeBlockMut, SignalBehavior, Socket};
+use syscall::{
+    error::*,
+    flag::{EventFlags, EVENT_READ, EVENT_WRITE},
+    schemev2::{Cqe, CqeOpcode, Opcode, Sqe},
+    Error,
+};
+use ipcd::flag::{F_GETRCVTIMEO, F_GETSNDTIMEO, F_SETRCVTIMEO, F_SETSNDTIMEO};
+
+use crate::timer::Timer;
+
+/// A scheme whose requests can block until something happens to one of
+/// its handles, served by a `Driver`
+pub trait BlockScheme: SchemeBlockMut {
+    fn socket(&self) -> &Socket;
+    fn blocked(&mut self) -> &mut Blocked;
+    /// Handle a request that's neither a call nor a cancellation
+    fn other_request(&mut self, _kind: RequestKind) -> Result<()> {
+        Ok(())
+    }
+    /// A request blocked on the handle was cancelled or timed out. By
+    /// default the ones behind it are tried again, so they can move up
+    fn unblocked(&mut self, id: usize, events: EventFlags) {
+        self.blocked().wake(id, events);
+    }
+    /// Take the file descriptor a `sendfd` on one of the handles passes
+    fn on_sendfd(&mut self, _request: &SendFdRequest) -> Result<usize> {
+        Err(Error::new(EOPNOTSUPP))
+    }
+}
+
+/// A `sendfd` on one of the scheme's handles. redox-scheme 0.2 passes it
+/// on as a plain call and answers it with EOPNOTSUPP, so `Driver` picks it
+/// out first
+pub struct SendFdRequest {
+    sqe: Sqe,
+}
+impl SendFdRequest {
+    /// Return the call as a `sendfd`, if it is one
+    pub fn from_call(req: &CallRequest) -> Option<Self> {
+        // A request is the raw entry wrapped transparently, redox-scheme
+        // just doesn't hand it out
+        let sqe = unsafe { mem::transmute::<Request, Sqe>(req.request()) };
+        matches!(Opcode::try_from_raw(sqe.opcode), Some(Opcode::Sendfd)).then_some(Self { sqe })
+    }
+
+    /// The handle the file descriptor is sent over
+    pub fn id(&self) -> usize {
+        self.sqe.args[0] as usize
+    }
+
+    /// Move the file descriptor out of the sender and into ipcd, and
+    /// return its number here
+    pub fn obtain_fd(&self, socket: &Socket) -> Result<usize> {
+        let mut fd = usize::MAX;
+        socket.inner().write(&Cqe {
+            flags: CqeOpcode::ObtainFd as u8,
+            extra_raw: [0; 3],
+            tag: self.sqe.tag,
+            result: &mut fd as *mut usize as u64,
+        })?;
+        Ok(fd)
+    }
+}
+
+/// Post an event for every handle open to something requests wait on, and
+/// wake up the requests waiting on it
+pub fn notify(socket: &Socket, blocked: &mut Blocked, handles: &[usize], id: usize, events: EventFlags) -> Result<()> {
+    for &handle_id in handles {
+        socket.post_fevent(handle_id, events.bits())?;
+    }
+    blocked.wake(id, events);
+    Ok(())
+}
+
+/// How long requests on a handle may block before failing with ETIMEDOUT,
+/// those waiting for EVENT_READ and those waiting for EVENT_WRITE. None
+/// waits forever
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timeouts {
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+impl Timeouts {
+    /// Return the timeout of requests waiting for `events`
+    pub fn get(&self, events: EventFlags) -> Option<Duration> {
+        if events.contains(EVENT_READ) { self.read } else { self.write }
+    }
+
+    /// Get or set a timeout in milliseconds, like SO_RCVTIMEO and
+    /// SO_SNDTIMEO, where 0 means none
+    pub fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize> {
+        let timeout = match cmd {
+            F_GETRCVTIMEO | F_SETRCVTIMEO => &mut self.read,
+            F_GETSNDTIMEO | F_SETSNDTIMEO => &mut self.write,
+            _ => return Err(Error::new(EINVAL))
+        };
+        if cmd == F_GETRCVTIMEO || cmd == F_GETSNDTIMEO {
+            return Ok(timeout.map_or(0, |timeout| timeout.as_millis() as usize));
+        }
+        *timeout = Some(Duration::from_millis(arg as u64)).filter(|timeout| !timeout.is_zero());
+        Ok(0)
+    }
+}
+
+/// The scheme's side of blocked requests: what the last one that couldn't
+/// finish is waiting for, which handles changed since, and how many
+/// requests are waiting on each handle.
+///
+/// Schemes whose handles share what they wait on, like a queue or a
+/// semaphore, wait on that instead. Its id comes from the same counter as
+/// the handle ids, so waking one never wakes requests waiting on the other
+#[derive(Default)]
+pub struct Blocked {
+    last: Option<(usize, EventFlags, Option<Duration>)>,
+    woken: VecDeque<(usize, EventFlags)>,
+    /// Readers (or acceptors) and writers (or connectors) per handle
+    queued: HashMap<usize, (usize, usize)>,
+}
+impl Blocked {
+    /// Leave the current request blocked until the handle gets an event
+    /// matching `events`, or until `timeout` runs out
+    pub fn block<T>(&mut self, id: usize, events: EventFlags, timeout: Option<Duration>) -> Result<Option<T>> {
+        let (readers, writers) = self.queued.entry(id).or_default();
+        if events.contains(EVENT_READ) {
+            *readers += 1;
+        } else {
+            *writers += 1;
+        }
+        self.block_aside(id, events, timeout)
+    }
+
+    /// Like `block`, but new requests don't line up behind this one
+    pub fn block_aside<T>(&mut self, id: usize, events: EventFlags, timeout: Option<Duration>) -> Result<Option<T>> {
+        self.last = Some((id, events, timeout));
+        Ok(None)
+    }
+
+    /// Return true if older requests are blocked waiting for `events` on
+    /// the handle. New ones have to line up behind them, so they all
+    /// finish in the order they were made
+    pub fn queued(&self, id: usize, events: EventFlags) -> bool {
+        self.queued.get(&id).is_some_and(|&(readers, writers)| {
+            if events.contains(EVENT_READ) { readers > 0 } else { writers > 0 }
+        })
+    }
+
+    /// Return how many requests are blocked waiting for `events` on the
+    /// handle
+    pub fn waiting(&self, id: usize, events: EventFlags) -> usize {
+        self.queued.get(&id).map_or(0, |&(readers, writers)| {
+            if events.contains(EVENT_READ) { readers } else { writers }
+        })
+    }
+
+    /// Take a request that stopped waiting for `events` on the handle out
+    /// of line, without trying the others again
+    pub fn leave(&mut self, id: usize, events: EventFlags) {
+        if let Some((readers, writers)) = self.queued.get_mut(&id) {
+            if events.contains(EVENT_READ) {
+                *readers = readers.saturating_sub(1);
+            } else {
+                *writers = writers.saturating_sub(1);
+            }
+            if (*readers, *writers) == (0, 0) {
+                self.queued.remove(&id);
+            }
+        }
+    }
+
+    /// Try requests blocked on a handle again
+    pub fn wake(&mut self, id: usize, events: EventFlags) {
+        self.woken.push_back((id, events));
+    }
+
+    /// Return the next handle whose blocked requests should be tried
+    /// again. They're all taken out of line, and the ones that still
+    /// can't finish line up again in the same order
+    pub fn next_woken(&mut self) -> Option<(usize, EventFlags)> {
+        let (id, events) = self.woken.pop_front()?;
+        if let Some((readers, writers)) = self.queued.get_mut(&id) {
+            if events.contains(EVENT_READ) {
+                *readers = 0;
+            }
+            if events.contains(EVENT_WRITE) {
+                *writers = 0;
+            }
+            if (*readers, *writers) == (0, 0) {
+                self.queued.remove(&id);
+            }
+        }
+        Some((id, events))
+    }
+
+    /// Return what the last request that couldn't finish is waiting for,
+    /// and for how long at most
+    pub fn take(&mut self) -> Option<(usize, EventFlags, Option<Duration>)> {
+        self.last.take()
+    }
+}
+
+/// A request that can't finish yet
+pub struct Waiter {
+    pub req: CallRequest,
+    /// When the request fails with ETIMEDOUT, on the monotonic clock
+    pub deadline: Option<Duration>,
+}
+
+/// Requests that can't finish yet, queued on the handle they're waiting
+/// for, so a change to one handle only retries what's blocked on it.
+/// On listeners, the readers are the ones accepting and the writers are
+/// the ones connecting.
+#[derive(Default)]
+pub struct WaitQueues {
+    readers: HashMap<usize, VecDeque<Waiter>>,
+    writers: HashMap<usize, VecDeque<Waiter>>,
+}
+impl WaitQueues {
+    /// Queue a request behind everything else waiting for `events` on the
+    /// handle
+    pub fn park(&mut self, id: usize, events: EventFlags, waiter: Waiter) {
+        let queues = if events.contains(EVENT_READ) {
+            &mut self.readers
+        } else {
+            &mut self.writers
+        };
+        queues.entry(id).or_default().push_back(waiter);
+    }
+
+    /// Take out every request waiting for any of `events` on the handle,
+    /// oldest first
+    pub fn wake(&mut self, id: usize, events: EventFlags) -> VecDeque<Waiter> {
+        let mut woken = VecDeque::new();
+        if events.contains(EVENT_READ) && let Some(readers) = self.readers.remove(&id) {
+            woken.extend(readers);
+        }
+        if events.contains(EVENT_WRITE) && let Some(writers) = self.writers.remove(&id) {
+            woken.extend(writers);
+        }
+        woken
+    }
+
+    /// Take out the request `f` matches, if it's still waiting, along with
+    /// what it was waiting for
+    pub fn cancel(&mut self, f: impl Fn(&CallRequest) -> bool) -> Option<(usize, EventFlags, Waiter)> {
+        let readers = self.readers.iter_mut().map(|(&id, queue)| (id, EVENT_READ, queue));
+        let writers = self.writers.iter_mut().map(|(&id, queue)| (id, EVENT_WRITE, queue));
+        for (id, events, queue) in readers.chain(writers) {
+            if let Some(i) = queue.iter().position(|waiter| f(&waiter.req)) {
+                return queue.remove(i).map(|waiter| (id, events, waiter));
+            }
+        }
+        None
+    }
+
+    /// Take out every request whose deadline passed by `now`, along with
+    /// what it was waiting for
+    pub fn expire(&mut self, now: Duration) -> Vec<(usize, EventFlags, Waiter)> {
+        let mut expired = Vec::new();
+        let readers = self.readers.iter_mut().map(|(&id, queue)| (id, EVENT_READ, queue));
+        let writers = self.writers.iter_mut().map(|(&id, queue)| (id, EVENT_WRITE, queue));
+        for (id, events, queue) in readers.chain(writers) {
+            let mut i = 0;
+            while i < queue.len() {
+                if queue[i].deadline.is_some_and(|deadline| deadline <= now) {
+                    expired.push((id, events, queue.remove(i).unwrap()));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        expired
+    }
+
+    /// Return the earliest deadline of any request
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.readers.values().chain(self.writers.values())
+            .flatten()
+            .filter_map(|waiter| waiter.deadline)
+            .min()
+    }
+
+    /// Take out every request
+    pub fn drain(&mut self) -> impl Iterator<Item = Waiter> + '_ {
+        self.readers.drain().chain(self.writers.drain()).flat_map(|(_, queue)| queue)
+    }
+}
+
+/// Serves the socket of a `BlockScheme`, and keeps requests that can't
+/// finish yet until what they wait for happens
+pub struct Driver<S> {
+    scheme: Option<S>,
+    waiting: WaitQueues,
+}
+impl<S: BlockScheme> Driver<S> {
+    pub fn new(scheme: S) -> Self {
+        Self {
+            scheme: Some(scheme),
+            waiting: WaitQueues::default(),
+        }
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.scheme.is_some()
+    }
+
+    /// Handle every request waiting on the socket. Once the scheme is
+    /// unmounted, everything still blocked fails with ENODEV
+    pub fn on_socket(&mut self, timer: &mut Timer) -> Result<()> {
+        let Some(ref mut scheme) = self.scheme else {
+            return Ok(());
+        };
+        let waiting = &mut self.waiting;
+
+        let eof = loop {
+            match scheme.socket().next_request(SignalBehavior::Restart) {
+                Ok(None) => break true,
+                Ok(Some(request)) => match request.kind() {
+                    RequestKind::Call(req) => handle(scheme, waiting, timer, Waiter { req, deadline: None })?,
+                    RequestKind::Cancellation(request) => {
+                        if let Some((id, events, waiter)) = waiting.cancel(|r| r.request().request_id() == request.id) {
+                            scheme.socket().write_response(Response::new(&waiter.req, Err(Error::new(EINTR))), SignalBehavior::Restart)?;
+                            scheme.unblocked(id, events);
+                        }
+                    }
+                    kind => scheme.other_request(kind)?,
+                },
+                Err(Error { errno: EAGAIN | EWOULDBLOCK }) => break false,
+                Err(error) => return Err(error),
+            }
+            retry(scheme, waiting, timer)?;
+        };
+
+        if eof && let Some(scheme) = self.scheme.take() {
+            for waiter in self.waiting.drain() {
+                scheme.socket().write_response(Response::new(&waiter.req, Err(Error::new(ENODEV))), SignalBehavior::Restart)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail every request whose deadline passed by `now` with ETIMEDOUT,
+    /// and make sure the timer goes off for the next one
+    pub fn on_timer(&mut self, timer: &mut Timer, now: Duration) -> Result<()> {
+        let Some(ref mut scheme) = self.scheme else {
+            return Ok(());
+        };
+        let waiting = &mut self.waiting;
+
+        for (id, events, waiter) in waiting.expire(now) {
+            scheme.socket().write_response(Response::new(&waiter.req, Err(Error::new(ETIMEDOUT))), SignalBehavior::Restart)?;
+            scheme.unblocked(id, events);
+        }
+        retry(scheme, waiting, timer)?;
+
+        if let Some(deadline) = waiting.next_deadline() {
+            timer.arm(deadline)?;
+        }
+        Ok(())
+    }
+}
+
+/// Handle a request, or queue it until the handle it's waiting for
+/// changes or it times out
+fn handle<S: BlockScheme>(scheme: &mut S, waiting: &mut WaitQueues, timer: &mut Timer, mut waiter: Waiter) -> Result<()> {
+    if let Some(request) = SendFdRequest::from_call(&waiter.req) {
+        let result = scheme.on_sendfd(&request);
+        scheme.socket().write_response(Response::new(&waiter.req, result), SignalBehavior::Restart)?;
+        return Ok(());
+    }
+    match waiter.req.handle_scheme_block_mut(scheme) {
+        Some(response) => {
+            scheme.socket().write_response(response, SignalBehavior::Restart)?;
+        }
+        None => {
+            let (id, events, timeout) = scheme.blocked().take().expect("ipcd: request blocked without saying on what");
+            // Requests tried again keep the deadline they got when they
+            // first blocked
+            if waiter.deadline.is_none() && let Some(timeout) = timeout {
+                let deadline = timer.now()? + timeout;
+                timer.arm(deadline)?;
+                waiter.deadline = Some(deadline);
+            }
+            waiting.park(id, events, waiter);
+        }
+    }
+    Ok(())
+}
+
+/// Try the requests blocked on handles that changed again, in the order
+/// they were made
+fn retry<S: BlockScheme>(scheme: &mut S, waiting: &mut WaitQueues, timer: &mut Timer) -> Result<()> {
+    while let Some((id, events)) = scheme.blocked().next_woken() {
+        for waiter in waiting.wake(id, events) {
+            handle(scheme, waiting, timer, waiter)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiter(tag: u32, deadline: Option<u64>) -> Waiter {
+        let sqe = Sqe { tag, ..Sqe::default() };
+        Waiter {
+            req: unsafe { mem::transmute::<Sqe, CallRequest>(sqe) },
+            deadline: deadline.map(Duration::from_secs),
+        }
+    }
+    fn tag(req: &CallRequest) -> u32 {
+        unsafe { mem::transmute::<Request, Sqe>(req.request()) }.tag
+    }
+    fn tags<'a>(waiters: impl IntoIterator<Item = &'a Waiter>) -> Vec<u32> {
+        waiters.into_iter().map(|waiter| tag(&waiter.req)).collect()
+    }
+
+    #[test]
+    fn wake_in_order() {
+        let mut waiting = WaitQueues::default();
+        for i in 1..=3 {
+            waiting.park(0, EVENT_READ, waiter(i, None));
+        }
+        waiting.park(0, EVENT_WRITE, waiter(4, None));
+        waiting.park(1, EVENT_READ, waiter(5, None));
+
+        assert_eq!(tags(&waiting.wake(0, EVENT_READ)), [1, 2, 3]);
+        assert!(waiting.wake(0, EVENT_READ).is_empty());
+        // Writers and other handles are left alone
+        assert_eq!(tags(&waiting.wake(0, EVENT_READ | EVENT_WRITE)), [4]);
+        assert_eq!(tags(&waiting.wake(1, EVENT_READ)), [5]);
+    }
+
+    #[test]
+    fn queued_until_woken() {
+        let mut blocked = Blocked::default();
+        assert_eq!(blocked.block::<()>(0, EVENT_READ, None), Ok(None));
+        assert_eq!(blocked.block::<()>(0, EVENT_READ, None), Ok(None));
+        assert_eq!(blocked.take(), Some((0, EVENT_READ, None)));
+        assert!(blocked.queued(0, EVENT_READ));
+        assert!(!blocked.queued(0, EVENT_WRITE));
+        assert_eq!(blocked.waiting(0, EVENT_READ), 2);
+
+        blocked.wake(0, EVENT_READ);
+        assert_eq!(blocked.next_woken(), Some((0, EVENT_READ)));
+        assert!(!blocked.queued(0, EVENT_READ));
+        assert_eq!(blocked.next_woken(), None);
+
+        // Whatever still can't finish lines up again
+        assert_eq!(blocked.block::<()>(0, EVENT_READ, None), Ok(None));
+        assert_eq!(blocked.waiting(0, EVENT_READ), 1);
+        blocked.leave(0, EVENT_READ);
+        assert!(!blocked.queued(0, EVENT_READ));
+    }
+
+    #[test]
+    fn cancel_keeps_order() {
+        let mut waiting = WaitQueues::default();
+        for i in 1..=3 {
+            waiting.park(0, EVENT_READ, waiter(i, None));
+        }
+
+        let (id, events, cancelled) = waiting.cancel(|req| tag(req) == 2).unwrap();
+        assert_eq!((id, events, tag(&cancelled.req)), (0, EVENT_READ, 2));
+        assert!(waiting.cancel(|req| tag(req) == 2).is_none());
+        assert_eq!(tags(&waiting.wake(0, EVENT_READ)), [1, 3]);
+    }
+
+    #[test]
+    fn expire_keeps_order() {
+        let mut waiting = WaitQueues::default();
+        waiting.park(0, EVENT_READ, waiter(1, Some(5)));
+        waiting.park(0, EVENT_READ, waiter(2, Some(1)));
+        waiting.park(0, EVENT_READ, waiter(3, None));
+        waiting.park(0, EVENT_READ, waiter(4, Some(2)));
+        assert_eq!(waiting.next_deadline(), Some(Duration::from_secs(1)));
+
+        let expired = waiting.expire(Duration::from_secs(2));
+        assert_eq!(tags(expired.iter().map(|(_, _, waiter)| waiter)), [2, 4]);
+        assert_eq!(waiting.next_deadline(), Some(Duration::from_secs(5)));
+        assert_eq!(tags(&waiting.wake(0, EVENT_READ)), [1, 3]);
+    }
+}